@@ -0,0 +1,172 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use clap::Args;
+use k8s_openapi::{
+    api::core::v1::Pod,
+    chrono::{DateTime, Utc},
+};
+use kube::{
+    Api, Client, ResourceExt,
+    api::{DeleteParams, ListParams},
+};
+use tracing::info;
+
+use crate::fwd::{clients::ClientPool, proxy::Proxy};
+
+#[derive(Args)]
+pub struct CleanupCommandArguments {
+    #[arg(
+        short,
+        long,
+        help = "Namespace to clean up, defaults to all namespaces"
+    )]
+    pub namespace: Option<String>,
+
+    #[arg(
+        short,
+        long = "context",
+        help = "The kubeconfig context to clean up, can be repeated"
+    )]
+    pub contexts: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "MINUTES",
+        default_value_t = 10,
+        help = "Only delete proxy pods whose heartbeat is older than this"
+    )]
+    pub older_than: u64,
+
+    #[arg(
+        long,
+        help = "List the proxy pods that would be deleted without deleting them"
+    )]
+    pub dry_run: bool,
+}
+
+pub async fn init(
+    CleanupCommandArguments {
+        namespace,
+        contexts,
+        older_than,
+        dry_run,
+    }: CleanupCommandArguments,
+) -> Result<()> {
+    let pool = ClientPool::default();
+    let max_silence = Duration::from_secs(older_than.saturating_mul(60));
+
+    let contexts = match contexts.as_slice() {
+        [] => vec![None],
+        contexts => contexts
+            .iter()
+            .map(|context| Some(context.as_str()))
+            .collect(),
+    };
+
+    for context in contexts {
+        let client = match context {
+            Some(context) => pool.get_or_insert(context).await?,
+            None => pool.get_default().await?,
+        };
+
+        clean(
+            client,
+            namespace.as_deref(),
+            context.unwrap_or("default"),
+            max_silence,
+            dry_run,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+async fn clean(
+    client: Client,
+    namespace: Option<&str>,
+    context: &str,
+    max_silence: Duration,
+    dry_run: bool,
+) -> Result<()> {
+    let api = match namespace {
+        Some(namespace) => Api::<Pod>::namespaced(client.clone(), namespace),
+        None => Api::<Pod>::all(client.clone()),
+    };
+
+    let pods = api
+        .list(&ListParams::default().labels(Proxy::SELECTOR))
+        .await?;
+
+    if pods.items.is_empty() {
+        info!("No proxy pods found in context {}", context);
+    }
+
+    for pod in pods.items {
+        let name = pod.name_any();
+        let namespace = pod.namespace().unwrap_or_default();
+        let owner = pod
+            .annotations()
+            .get(Proxy::OWNER)
+            .map_or("unknown", String::as_str);
+
+        let age = pod
+            .creation_timestamp()
+            .and_then(|created| (Utc::now() - created.0).to_std().ok())
+            .unwrap_or_default();
+
+        // The heartbeat is renewed by the kubef running the proxy, so a recent
+        // one means it is still in use whatever the age of the pod
+        let silence = pod
+            .annotations()
+            .get(Proxy::HEARTBEAT)
+            .and_then(|heartbeat| heartbeat.parse().ok())
+            .and_then(|heartbeat| DateTime::from_timestamp(heartbeat, 0))
+            .and_then(|heartbeat| (Utc::now() - heartbeat).to_std().ok())
+            .unwrap_or(age);
+
+        let terminated = pod
+            .status
+            .as_ref()
+            .and_then(|status| status.phase.as_deref())
+            .is_some_and(|phase| matches!(phase, "Failed" | "Succeeded"));
+
+        let pod_ref = format!("{context}/{namespace}/{name}");
+        let details = format!(
+            "age {}, last heartbeat {} ago, owner {}",
+            format_age(age),
+            format_age(silence),
+            owner
+        );
+
+        if silence < max_silence && !terminated {
+            info!("Keeping proxy pod {} ({})", pod_ref, details);
+
+            continue;
+        }
+
+        if dry_run {
+            info!("Would delete proxy pod {} ({})", pod_ref, details);
+
+            continue;
+        }
+
+        Api::<Pod>::namespaced(client.clone(), &namespace)
+            .delete(&name, &DeleteParams::default())
+            .await?;
+
+        info!("Deleted proxy pod {} ({})", pod_ref, details);
+    }
+
+    Ok(())
+}
+
+fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        secs @ 86400.. => format!("{}d{}h", secs / 86400, secs % 86400 / 3600),
+        secs @ 3600.. => format!("{}h{}m", secs / 3600, secs % 3600 / 60),
+        secs @ 60.. => format!("{}m", secs / 60),
+        secs => format!("{secs}s"),
+    }
+}
@@ -26,7 +26,8 @@ pub async fn init(
 
     let forwarder = Forwarder::default()
         .with_context(context)
-        .with_loopback(config.loopback);
+        .with_loopback(config.loopback)
+        .with_proxy_template(config.proxy.as_ref());
 
     match resources {
         Either::Left(resource) => forwarder.forward(resource).await?,
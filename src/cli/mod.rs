@@ -6,6 +6,7 @@ use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberI
 
 use crate::env::{LOGO, PKG_NAME, PKG_RELEASE};
 
+mod cleanup;
 mod forward;
 mod proxy;
 
@@ -27,6 +28,8 @@ enum Commands {
     Forward(forward::ForwardCommandArguments),
     #[command(about = "Proxy an internal ip address")]
     Proxy(proxy::ProxyCommandArguments),
+    #[command(about = "Delete proxy pods left behind")]
+    Cleanup(cleanup::CleanupCommandArguments),
 }
 
 pub async fn init() -> ExitCode {
@@ -45,6 +48,7 @@ pub async fn init() -> ExitCode {
     let output = match args.command {
         Some(Commands::Forward(args)) => forward::init(args).await,
         Some(Commands::Proxy(args)) => proxy::init(args).await,
+        Some(Commands::Cleanup(args)) => cleanup::init(args).await,
         None => {
             if let Some(target) = args.target {
                 forward::init(forward::ForwardCommandArguments {
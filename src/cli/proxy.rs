@@ -1,15 +1,17 @@
-use std::{net::SocketAddr, sync::Arc};
-
-use crate::fwd::{
-    Forwarder,
-    clients::ClientPool,
-    proxy::{Proxy, ProxyDestination},
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::{
+    cnf,
+    fwd::{
+        clients::ClientPool,
+        proxy::{Listener, Proxy, ProxyDestination, ProxyTarget},
+    },
 };
 use anyhow::Result;
 use clap::{Args, ValueEnum};
 use k8s_openapi::api::core::v1::Pod;
 use kube::Api;
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, UdpSocket};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 #[derive(ValueEnum, Clone)]
@@ -26,14 +28,25 @@ pub struct ProxyCommandArguments {
     #[arg(short, long, help = "Local address to listen on")]
     pub bind: SocketAddr,
 
-    #[arg(short, long, help = "Remote address to forward to")]
-    pub target: SocketAddr,
+    #[arg(
+        short,
+        long,
+        help = "Remote host:port to forward to, resolved from within the cluster"
+    )]
+    pub target: ProxyTarget,
 
     #[arg(short, long, default_value = "tcp", help = "Protocol to use")]
     pub protocol: ProxyProtocol,
 
     #[arg(short, long, help = "The kubeconfig context to use")]
     pub context: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "How long to wait for the proxy pod to be ready"
+    )]
+    pub timeout: Option<u64>,
 }
 
 pub async fn init(
@@ -43,7 +56,7 @@ pub async fn init(
         namespace,
         context,
         protocol,
-        ..
+        timeout,
     }: ProxyCommandArguments,
 ) -> Result<()> {
     let tracker = TaskTracker::new();
@@ -55,60 +68,46 @@ pub async fn init(
 
     let token = CancellationToken::new();
     let namespace = namespace.as_deref().unwrap_or(client.default_namespace());
-    let socket = TcpListener::bind(bind_addr).await?;
     let api = Api::<Pod>::namespaced(client.clone(), namespace);
     let api_ptr = Arc::new(api.clone());
-    let proxy = Proxy::new(api);
+    // The config file is optional here, it only customizes the proxy pod
+    let template = cnf::find().await?.and_then(|config| config.proxy.as_ref());
+
+    let proxy = Proxy::new(api)
+        .with_template(template)
+        .with_timeout(timeout.map(Duration::from_secs));
 
-    let destination = match protocol {
+    let destination = match &protocol {
         ProxyProtocol::Tcp => ProxyDestination::Tcp(target),
         ProxyProtocol::Udp => ProxyDestination::Udp(target),
     };
 
     proxy.spawn(&destination).await?;
 
-    tracker.spawn(bind(
+    let listener = match protocol {
+        ProxyProtocol::Tcp => Listener::Tcp(TcpListener::bind(bind_addr).await?),
+        ProxyProtocol::Udp => Listener::Udp(UdpSocket::bind(bind_addr).await?),
+    };
+
+    tracker.spawn(listener.serve(
         api_ptr,
         proxy.get_name(),
-        socket,
         token.child_token(),
         tracker.clone(),
     ));
 
-    tokio::select! {
+    let exit = tokio::select! {
         biased;
-        _ = tokio::signal::ctrl_c() => {},
-        _ = proxy.wait_until_exit() => {},
-    }
+        _ = tokio::signal::ctrl_c() => Ok(()),
+        exit = proxy.wait_until_exit() => exit,
+        exit = proxy.keep_alive() => exit,
+    };
 
     token.cancel();
     tracker.close();
 
     tracker.wait().await;
-    proxy.abort().await?;
-
-    Ok(())
-}
+    let abort = proxy.abort().await;
 
-pub async fn bind(
-    api: Arc<Api<Pod>>,
-    name: String,
-    socket: TcpListener,
-    token: CancellationToken,
-    tracker: TaskTracker,
-) -> Result<()> {
-    loop {
-        tokio::select! {
-            biased;
-            () = token.cancelled() => break,
-            Ok((connection, _)) = socket.accept() => {
-                let api = api.clone();
-                let token = token.child_token();
-
-                tracker.spawn(Forwarder::upstream(api, Proxy::PORT, name.clone(), connection, token));
-            }
-        }
-    }
-
-    Ok(())
+    exit.and(abort)
 }
@@ -1,6 +1,6 @@
-use std::env;
+use std::{env, fmt, path::PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use tokio::{sync::OnceCell, task};
 
 pub mod schema;
@@ -8,31 +8,151 @@ pub mod schema;
 static CNF: OnceCell<schema::Config> = OnceCell::const_new();
 
 pub async fn extract() -> Result<&'static schema::Config> {
-    let xdg = xdg::BaseDirectories::with_prefix("kubef");
+    match find().await? {
+        Some(config) => Ok(config),
+        None => anyhow::bail!("Config file not found at {}", path()?.display()),
+    }
+}
+
+// Like extract, for commands that work without a config file: only a
+// missing file is tolerated, an invalid one is still an error
+pub async fn find() -> Result<Option<&'static schema::Config>> {
+    if let Some(config) = CNF.get() {
+        return Ok(Some(config));
+    }
+
+    let path = path()?;
+    let parser = task::spawn_blocking(move || {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let file = std::fs::File::open(path)?;
+        let config: schema::Config = serde_yaml_ng::from_reader(file)?;
 
-    let path = match env::var("KUBEF_CONFIG") {
-        Ok(val) => std::path::PathBuf::from(val),
-        Err(_) => xdg
-            .place_config_file("config.yaml")
-            .expect("Failed to create default config file"),
+        Ok::<_, anyhow::Error>(Some(config))
+    });
+
+    let Some(config) = parser.await?? else {
+        return Ok(None);
     };
 
-    let config = CNF
-        .get_or_try_init(|| async {
-            let parser = task::spawn_blocking(|| {
-                if !path.exists() {
-                    anyhow::bail!("Config file not found at {}", path.display());
-                }
+    Ok(Some(CNF.get_or_init(|| async { config }).await))
+}
+
+fn path() -> Result<PathBuf> {
+    match env::var("KUBEF_CONFIG") {
+        Ok(val) => Ok(PathBuf::from(val)),
+        Err(_) => xdg::BaseDirectories::with_prefix("kubef")
+            .get_config_file("config.yaml")
+            .context("Cannot locate the config directory, set KUBEF_CONFIG instead"),
+    }
+}
 
-                let file = std::fs::File::open(path)?;
-                let config: schema::Config = serde_yaml_ng::from_reader(file)?;
+impl schema::Ports {
+    pub fn mappings(&self) -> &[schema::PortMapping] {
+        match self {
+            schema::Ports::Single(mapping) => std::slice::from_ref(mapping),
+            schema::Ports::Multiple(mappings) => mappings,
+        }
+    }
+}
+
+impl fmt::Display for schema::RemotePort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            schema::RemotePort::Number(port) => write!(f, "{port}"),
+            schema::RemotePort::Name(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+impl fmt::Display for schema::Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            schema::Protocol::Tcp => write!(f, "TCP"),
+            schema::Protocol::Udp => write!(f, "UDP"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BackendRef<'a> {
+    pub namespace: &'a str,
+    pub contexts: Vec<&'a str>,
+    pub selector: &'a schema::ResourceSelector,
+    pub discovery: schema::Discovery,
+    pub weight: u32,
+}
 
-                Ok::<_, anyhow::Error>(config)
-            });
+impl schema::Resource {
+    pub fn backends(&self) -> Result<Vec<BackendRef<'_>>> {
+        let discovery = self.discovery.unwrap_or_default();
 
-            parser.await?
-        })
-        .await?;
+        // Health checks connect over TCP, which the remote port of a UDP resource does not speak
+        if matches!(self.protocol, Some(schema::Protocol::Udp))
+            && self
+                .health
+                .as_ref()
+                .is_some_and(|check| check.port.is_none())
+        {
+            anyhow::bail!(
+                "Resource {} forwards UDP, its health check needs a TCP port",
+                self.alias
+            );
+        }
+        let contexts: Vec<_> = match (&self.context, &self.contexts) {
+            (Some(_), Some(_)) => anyhow::bail!(
+                "Resource {} cannot have both a context and a list of contexts",
+                self.alias
+            ),
+            (context, contexts) => contexts
+                .iter()
+                .flatten()
+                .chain(context)
+                .map(String::as_str)
+                .collect(),
+        };
 
-    Ok(config)
+        match (&self.selector, &self.backends) {
+            (Some(selector), None) => Ok(vec![BackendRef {
+                namespace: &self.namespace,
+                contexts,
+                selector,
+                discovery,
+                weight: 1,
+            }]),
+            // Backends are drawn by weight before the policy picks a pod within
+            // one, so policies that pin or compare pods would only hold per backend
+            (None, Some(backends))
+                if backends.len() > 1
+                    && !matches!(
+                        self.policy.unwrap_or_default(),
+                        schema::SelectorPolicy::RoundRobin | schema::SelectorPolicy::Random
+                    ) =>
+            {
+                anyhow::bail!(
+                    "Resource {} spreads across weighted backends, which only supports the roundrobin and random policies",
+                    self.alias
+                )
+            }
+            (None, Some(backends)) if !backends.is_empty() => Ok(backends
+                .iter()
+                .map(|backend| BackendRef {
+                    namespace: backend.namespace.as_deref().unwrap_or(&self.namespace),
+                    contexts: match &backend.context {
+                        Some(context) => vec![context.as_str()],
+                        None => contexts.clone(),
+                    },
+                    selector: &backend.selector,
+                    discovery,
+                    weight: backend.weight.unwrap_or(1),
+                })
+                .collect()),
+            _ => anyhow::bail!(
+                "Resource {} needs either a selector, a list of backends or a proxy",
+                self.alias
+            ),
+        }
+    }
 }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use ipnet::IpNet;
 use schemars::JsonSchema;
@@ -11,6 +11,55 @@ pub struct Config {
     pub groups: HashMap<String, Vec<Resource>>,
     #[schemars(with = "Option<String>")]
     pub loopback: Option<IpNet>,
+    pub proxy: Option<ProxyTemplate>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyTemplate {
+    pub image: Option<String>,
+    pub relay_image: Option<String>,
+    pub image_pull_secrets: Option<Vec<String>>,
+    pub service_account: Option<String>,
+    pub labels: Option<BTreeMap<String, String>>,
+    pub annotations: Option<BTreeMap<String, String>>,
+    pub node_selector: Option<BTreeMap<String, String>>,
+    pub tolerations: Option<Vec<ProxyToleration>>,
+    pub resources: Option<ProxyResources>,
+    pub security_context: Option<ProxySecurityContext>,
+    pub ready_timeout_seconds: Option<u64>,
+    pub expire_after_seconds: Option<u64>,
+    pub watchdog: Option<bool>,
+    pub active_deadline_seconds: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyToleration {
+    pub key: Option<String>,
+    pub operator: Option<String>,
+    pub value: Option<String>,
+    pub effect: Option<String>,
+    pub toleration_seconds: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyResources {
+    pub requests: Option<BTreeMap<String, String>>,
+    pub limits: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ProxySecurityContext {
+    pub run_as_non_root: Option<bool>,
+    pub run_as_user: Option<i64>,
+    pub run_as_group: Option<i64>,
+    pub read_only_root_filesystem: Option<bool>,
+    pub allow_privilege_escalation: Option<bool>,
+    pub drop_capabilities: Option<Vec<String>>,
+    pub seccomp_profile: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
@@ -19,18 +68,59 @@ pub struct Resource {
     pub alias: String,
     pub namespace: String,
     pub context: Option<String>,
+    pub contexts: Option<Vec<String>>,
     pub policy: Option<SelectorPolicy>,
-    pub selector: ResourceSelector,
+    pub selector: Option<ResourceSelector>,
+    pub backends: Option<Vec<WeightedBackend>>,
+    pub proxy: Option<String>,
     pub ports: Ports,
+    pub protocol: Option<Protocol>,
+    pub include_not_ready: Option<bool>,
+    pub discovery: Option<Discovery>,
+    pub retry: Option<Retry>,
+    pub health: Option<HealthCheck>,
+    pub prefer: Option<Preference>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct WeightedBackend {
+    pub namespace: Option<String>,
+    pub context: Option<String>,
+    pub selector: ResourceSelector,
+    pub weight: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum Ports {
+    Single(PortMapping),
+    Multiple(Vec<PortMapping>),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
 #[serde(deny_unknown_fields)]
-pub struct Ports {
-    pub remote: u16,
+pub struct PortMapping {
+    pub remote: RemotePort,
     pub local: Option<u16>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum RemotePort {
+    Number(u16),
+    Name(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Copy, Default, JsonSchema)]
 #[serde(rename_all = "lowercase")]
 #[serde(deny_unknown_fields)]
@@ -38,6 +128,42 @@ pub enum SelectorPolicy {
     Sticky,
     #[default]
     RoundRobin,
+    LeastConnections,
+    IpHash,
+    Random,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Retry {
+    pub attempts: u32,
+    pub penalty_seconds: Option<u64>,
+    pub grace_milliseconds: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    pub port: Option<RemotePort>,
+    pub path: Option<String>,
+    pub interval_seconds: Option<u64>,
+    pub timeout_seconds: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Preference {
+    pub zone: Option<String>,
+    pub node: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+#[serde(deny_unknown_fields)]
+pub enum Discovery {
+    #[default]
+    Pods,
+    EndpointSlices,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
@@ -45,7 +171,53 @@ pub enum SelectorPolicy {
 #[serde(tag = "type", content = "match")]
 #[serde(deny_unknown_fields)]
 pub enum ResourceSelector {
-    Label(Vec<(String, String)>),
+    Label(Vec<LabelMatch>),
     Deployment(String),
+    StatefulSet(StatefulSetMatch),
+    DaemonSet(String),
+    ReplicaSet(String),
+    Job(String),
     Service(String),
+    Pod(String),
+    Custom(CustomMatch),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum LabelMatch {
+    Equal((String, String)),
+    Expression(LabelExpression),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LabelExpression {
+    pub key: String,
+    pub operator: LabelOperator,
+    #[serde(default)]
+    pub values: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Copy, JsonSchema)]
+pub enum LabelOperator {
+    In,
+    NotIn,
+    Exists,
+    DoesNotExist,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(untagged)]
+pub enum StatefulSetMatch {
+    Name(String),
+    Ordinal { name: String, ordinal: u32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[serde(deny_unknown_fields)]
+pub struct CustomMatch {
+    pub api_version: String,
+    pub kind: String,
+    pub name: String,
 }
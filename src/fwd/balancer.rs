@@ -0,0 +1,202 @@
+use std::sync::{
+    Arc, OnceLock,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Result;
+use futures::future;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{Api, Client};
+use tracing::info;
+
+use crate::{
+    cnf::{
+        BackendRef,
+        schema::{Protocol, ProxyTemplate},
+    },
+    fwd::{
+        proxy::{Proxy, ProxyDestination},
+        watcher::{self, Options, Selection, Watcher},
+    },
+};
+
+#[derive(Clone)]
+pub struct Upstream {
+    pub api: Arc<Api<Pod>>,
+    pub watcher: Arc<Watcher>,
+    pub selection: Arc<Selection>,
+    pub relay: Option<Arc<Proxy>>,
+}
+
+impl Upstream {
+    pub async fn new(
+        client: Client,
+        backend: &BackendRef<'_>,
+        options: Options,
+        relay: Option<Proxy>,
+    ) -> Result<Self> {
+        let api = Api::<Pod>::namespaced(client.clone(), backend.namespace);
+        let selection = watcher::select(&client, backend).await?;
+        let watcher = Watcher::new(client, backend.namespace, &selection, options).await?;
+
+        if let Some(relay) = &relay {
+            relay.spawn(&ProxyDestination::Relay).await?;
+        }
+
+        Ok(Self {
+            api: Arc::new(api),
+            watcher: Arc::new(watcher),
+            selection: Arc::new(selection),
+            relay: relay.map(Arc::new),
+        })
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.watcher.is_connected() && !self.watcher.is_empty()
+    }
+}
+
+pub struct Group {
+    weight: u32,
+    contexts: Vec<String>,
+    upstreams: Vec<OnceLock<Upstream>>,
+    active: AtomicUsize,
+}
+
+impl Group {
+    pub fn new(weight: u32, contexts: Vec<String>) -> Self {
+        Self {
+            weight,
+            upstreams: contexts.iter().map(|_| OnceLock::new()).collect(),
+            contexts,
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.upstreams.len()
+    }
+
+    pub fn context(&self, index: usize) -> &str {
+        &self.contexts[index]
+    }
+
+    fn set(&self, index: usize, upstream: Upstream) -> &Upstream {
+        self.upstreams[index].get_or_init(|| upstream)
+    }
+
+    pub fn upstreams(&self) -> impl Iterator<Item = &Upstream> {
+        self.upstreams.iter().filter_map(OnceLock::get)
+    }
+
+    // The first available context wins, so the group falls back to the primary once it recovers
+    pub fn active(&self) -> Option<&Upstream> {
+        let index = self
+            .upstreams
+            .iter()
+            .position(|upstream| upstream.get().is_some_and(Upstream::is_available))?;
+
+        let previous = self.active.swap(index, Ordering::Relaxed);
+        if previous != index {
+            info!(
+                "Switching from context {} to {}",
+                self.contexts[previous], self.contexts[index]
+            );
+        }
+
+        self.upstreams[index].get()
+    }
+}
+
+pub struct Member {
+    pub group: Arc<Group>,
+    pub index: usize,
+    pub client: Client,
+    pub backend: BackendRef<'static>,
+    pub protocol: Protocol,
+    pub template: Option<&'static ProxyTemplate>,
+}
+
+impl Member {
+    pub fn context(&self) -> &str {
+        self.group.context(self.index)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.group.upstreams[self.index].get().is_some()
+    }
+
+    pub async fn connect(&self, options: Options) -> Result<&Upstream> {
+        // Pods only accept TCP through port-forward, so datagrams go through a relay pod
+        let relay = matches!(self.protocol, Protocol::Udp).then(|| {
+            let api = Api::<Pod>::namespaced(self.client.clone(), self.backend.namespace);
+
+            Proxy::new(api).with_template(self.template)
+        });
+
+        let upstream = Upstream::new(self.client.clone(), &self.backend, options, relay).await?;
+
+        Ok(self.group.set(self.index, upstream))
+    }
+}
+
+pub struct Balancer {
+    groups: Vec<Arc<Group>>,
+}
+
+impl Balancer {
+    pub fn new(groups: Vec<Arc<Group>>) -> Self {
+        Self { groups }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups
+            .iter()
+            .all(|group| group.weight == 0 || group.active().is_none())
+    }
+
+    pub async fn next(&self) -> Result<()> {
+        let watchers: Vec<_> = self
+            .groups
+            .iter()
+            .flat_map(|group| group.upstreams())
+            .map(|upstream| Box::pin(upstream.watcher.next()))
+            .collect();
+
+        if watchers.is_empty() {
+            return future::pending().await;
+        }
+
+        let (next, _, _) = future::select_all(watchers).await;
+
+        next
+    }
+
+    pub fn pick(&self) -> Option<Upstream> {
+        let available: Vec<_> = self
+            .groups
+            .iter()
+            .filter(|group| group.weight > 0)
+            .filter_map(|group| group.active().map(|upstream| (group.weight, upstream)))
+            .collect();
+
+        let total: u32 = available.iter().map(|(weight, _)| weight).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut point = rand::random_range(0..total);
+
+        available
+            .into_iter()
+            .find(|(weight, _)| {
+                if point < *weight {
+                    return true;
+                }
+
+                point -= weight;
+                false
+            })
+            .map(|(_, upstream)| upstream.clone())
+    }
+}
@@ -0,0 +1,51 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Clone, Default)]
+pub struct Connections {
+    inner: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+pub struct ConnectionGuard {
+    connections: Connections,
+    name: String,
+}
+
+impl Connections {
+    pub fn acquire(&self, name: impl Into<String>) -> ConnectionGuard {
+        let name = name.into();
+
+        *self.lock().entry(name.clone()).or_default() += 1;
+
+        ConnectionGuard {
+            connections: self.clone(),
+            name,
+        }
+    }
+
+    pub fn count(&self, name: &str) -> usize {
+        self.lock().get(name).copied().unwrap_or_default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, usize>> {
+        self.inner
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock();
+
+        if let Some(count) = connections.get_mut(&self.name) {
+            *count -= 1;
+
+            if *count == 0 {
+                connections.remove(&self.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::{collections::HashMap, hash::Hash};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+};
+use tokio_util::{
+    bytes::Bytes,
+    codec::{Framed, LengthDelimitedCodec},
+};
+
+// Datagrams travel over the TCP tunnel prefixed by their length on two bytes,
+// the same framing used by DNS over TCP
+pub const MAX_LENGTH: usize = u16::MAX as usize;
+
+const FLOW_CAPACITY: usize = 64;
+
+pub fn framed<T: AsyncRead + AsyncWrite>(stream: T) -> Framed<T, LengthDelimitedCodec> {
+    LengthDelimitedCodec::builder()
+        .length_field_length(2)
+        .max_frame_length(MAX_LENGTH)
+        .new_framed(stream)
+}
+
+pub struct Flows<K> {
+    inner: HashMap<K, mpsc::Sender<Bytes>>,
+}
+
+impl<K> Default for Flows<K> {
+    fn default() -> Self {
+        Self {
+            inner: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq> Flows<K> {
+    /// Hands the datagram to the live flow of the client, or opens a new one
+    /// and returns its receiving end
+    pub fn route(&mut self, key: K, datagram: Bytes) -> Option<mpsc::Receiver<Bytes>> {
+        if let Some(flow) = self.inner.get(&key).filter(|flow| !flow.is_closed()) {
+            // Like UDP itself, drop datagrams the tunnel cannot keep up with
+            let _ = flow.try_send(datagram);
+            return None;
+        }
+
+        let (sender, receiver) = mpsc::channel(FLOW_CAPACITY);
+        let _ = sender.try_send(datagram);
+
+        self.inner.retain(|_, flow| !flow.is_closed());
+        self.inner.insert(key, sender);
+
+        Some(receiver)
+    }
+}
@@ -0,0 +1,151 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use futures::{StreamExt, stream};
+use k8s_openapi::api::core::v1::Pod;
+use kube::Api;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::MissedTickBehavior,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::{
+    cnf::schema::{HealthCheck, Protocol, RemotePort},
+    fwd::{
+        ports,
+        watcher::{Selection, Watcher},
+    },
+};
+
+const DEFAULT_INTERVAL: u64 = 10;
+const DEFAULT_TIMEOUT: u64 = 3;
+
+// Every probe opens a port-forward, so large deployments are checked a few at a time
+const MAX_PROBES: usize = 8;
+
+// Dial failures are reported by the kubelet right after the tunnel is opened
+const TCP_GRACE: Duration = Duration::from_millis(500);
+
+pub async fn watch(
+    api: Arc<Api<Pod>>,
+    watcher: Arc<Watcher>,
+    selection: Arc<Selection>,
+    check: &'static HealthCheck,
+    remote: &'static RemotePort,
+    token: CancellationToken,
+) {
+    let remote = check.port.as_ref().unwrap_or(remote);
+    let timeout = Duration::from_secs(check.timeout_seconds.unwrap_or(DEFAULT_TIMEOUT));
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        check.interval_seconds.unwrap_or(DEFAULT_INTERVAL),
+    ));
+
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            biased;
+            () = token.cancelled() => break,
+            _ = interval.tick() => {},
+        }
+
+        let backends = watcher.backends();
+        let probes: Vec<_> = backends
+            .iter()
+            .map(|backend| {
+                let port = ports::resolve(backend, remote, &selection.ports, Protocol::Tcp);
+
+                verify(&api, backend.name(), port, remote, check, timeout)
+            })
+            .collect();
+
+        let results: Vec<_> = stream::iter(probes).buffered(MAX_PROBES).collect().await;
+
+        let unhealthy: HashSet<_> = backends
+            .iter()
+            .zip(&results)
+            .filter(|(_, result)| result.is_err())
+            .map(|(backend, _)| backend.uid().to_owned())
+            .collect();
+
+        let previous = watcher.set_unhealthy(unhealthy);
+
+        for (backend, result) in backends.iter().zip(results) {
+            match (result, previous.contains(backend.uid())) {
+                (Err(e), false) => warn!("Pod {} failed its health check: {:#}", backend.name(), e),
+                (Ok(()), true) => info!("Pod {} recovered", backend.name()),
+                _ => debug!("Pod {} health unchanged", backend.name()),
+            }
+        }
+    }
+}
+
+async fn verify(
+    api: &Api<Pod>,
+    pod_name: &str,
+    port: Option<u16>,
+    remote: &RemotePort,
+    check: &HealthCheck,
+    timeout: Duration,
+) -> Result<()> {
+    let port = port.with_context(|| format!("Cannot resolve port {remote}"))?;
+
+    tokio::time::timeout(timeout, probe(api, pod_name, port, check.path.as_deref()))
+        .await
+        .context("Health check timed out")?
+}
+
+async fn probe(api: &Api<Pod>, pod_name: &str, port: u16, path: Option<&str>) -> Result<()> {
+    let mut forwarding = api.portforward(pod_name, &[port]).await?;
+    let stream = forwarding
+        .take_stream(port)
+        .context("Failed to take stream")?;
+
+    let closer = forwarding
+        .take_error(port)
+        .context("Failed to take error stream")?;
+
+    let result = tokio::select! {
+        Some(e) = closer => Err(anyhow::anyhow!(e)),
+        result = async {
+            if let Some(path) = path {
+                http(stream, path).await
+            } else {
+                tokio::time::sleep(TCP_GRACE).await;
+
+                Ok(())
+            }
+        } => result,
+    };
+
+    forwarding.abort();
+
+    result
+}
+
+async fn http(mut stream: impl AsyncRead + AsyncWrite + Unpin, path: &str) -> Result<()> {
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buffer = Vec::with_capacity(64);
+    while !buffer.contains(&b'\n') {
+        if stream.read_buf(&mut buffer).await? == 0 || buffer.len() > 1024 {
+            anyhow::bail!("Invalid HTTP response");
+        }
+    }
+
+    let line = String::from_utf8_lossy(&buffer);
+    let status: u16 = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .context("Invalid HTTP status line")?;
+
+    if !(200..400).contains(&status) {
+        anyhow::bail!("Unhealthy HTTP status {status}");
+    }
+
+    Ok(())
+}
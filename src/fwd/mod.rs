@@ -1,29 +1,50 @@
-use std::sync::Arc;
+use std::{io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration};
 
 use crate::{
-    cnf::schema::Resource,
+    cnf::schema::{Protocol, ProxyTemplate, RemotePort, Resource, SelectorPolicy},
     fwd::{
+        balancer::{Balancer, Group, Member, Upstream},
         clients::ClientPool,
+        datagram::Flows,
+        proxy::{Listener, Proxy, ProxyDestination, ProxyTarget},
         sockets::{LoopbackToken, SocketPool},
+        tunnel::Tunnel,
     },
 };
 use anyhow::{Context, Result};
 use either::Either;
-use futures::future;
+use futures::{SinkExt, StreamExt, future};
 use ipnet::IpNet;
 use k8s_openapi::api::core::v1::Pod;
-use kube::{Api, ResourceExt};
-use tokio::net::{TcpSocket, TcpStream};
-use tokio_util::{sync::CancellationToken, task::TaskTracker};
-use tracing::{Level, debug, info, instrument};
+use kube::Api;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpSocket, TcpStream, UdpSocket},
+    sync::mpsc,
+    time::Instant,
+};
+use tokio_util::{bytes::Bytes, sync::CancellationToken, task::TaskTracker};
+use tracing::{Level, debug, info, instrument, warn};
 
+pub mod balancer;
 pub mod clients;
+pub mod connections;
+pub mod datagram;
+pub mod health;
+pub mod ports;
 pub mod proxy;
 pub mod sockets;
+pub mod tunnel;
 pub mod watcher;
 
 pub type Target<'a> = Either<&'a Resource, &'a [Resource]>;
 
+const DEFAULT_PENALTY: u64 = 10;
+const DEFAULT_GRACE: u64 = 100;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(Default)]
 pub struct Forwarder<'ctx> {
     pool: ClientPool<'ctx>,
@@ -31,6 +52,7 @@ pub struct Forwarder<'ctx> {
     tracker: TaskTracker,
     token: CancellationToken,
     context: Option<&'ctx str>,
+    template: Option<&'static ProxyTemplate>,
 }
 
 impl<'ctx> Forwarder<'ctx> {
@@ -39,66 +61,181 @@ impl<'ctx> Forwarder<'ctx> {
         self
     }
 
+    pub fn with_proxy_template(
+        mut self,
+        template: impl Into<Option<&'static ProxyTemplate>>,
+    ) -> Self {
+        self.template = template.into();
+        self
+    }
+
     pub fn with_loopback(mut self, loopback: impl Into<Option<IpNet>>) -> Self {
         self.sockets = self.sockets.with_loopback(loopback.into());
         self
     }
 
-    #[instrument(err, skip(self, socket, resource, ltoken), fields(resource = %resource.alias))]
+    #[instrument(err, skip(self, sockets, resource, ltoken), fields(resource = %resource.alias))]
     pub async fn bind<'fut>(
         &self,
-        socket: TcpSocket,
+        sockets: Vec<TcpSocket>,
         resource: &'static Resource,
         ltoken: Option<LoopbackToken>,
     ) -> Result<impl Future<Output = Result<()>> + 'fut> {
         let token = self.token.child_token();
         let tracker = self.tracker.clone();
 
-        let policy = resource.policy.unwrap_or_default();
-        let context = resource.context.as_deref().or(self.context);
+        let options = watcher::Options::from(resource);
+        let (groups, members) = self.groups(resource).await?;
 
-        let client = match context {
-            Some(context) => self.pool.get_or_insert(context).await?,
-            _ => self.pool.get_default().await?,
-        };
-
-        let server = socket.listen(1024)?;
+        let servers = sockets
+            .into_iter()
+            .zip(resource.ports.mappings())
+            .map(|(socket, mapping)| {
+                let server = socket.listen(1024)?;
+
+                info!(
+                    "Listening TCP on {} forwarded to {}:{}",
+                    server.local_addr()?,
+                    resource.alias,
+                    mapping.remote
+                );
+
+                Ok((server, mapping))
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-        let api = Api::<Pod>::namespaced(client.clone(), &resource.namespace);
-        let api_ptr = Arc::new(api.clone());
+        if servers.is_empty() {
+            anyhow::bail!("Resource {} has no ports", resource.alias);
+        }
 
-        info!(
-            "Listening TCP on {} forwarded to {}",
-            server.local_addr()?,
-            resource.alias
-        );
+        Forwarder::check_policy(resource, &options, servers[0].0.local_addr()?);
 
         // TODO: How do we capture the error?
         let future = async move {
-            let selector = watcher::select(&client, resource).await?;
-            let mut watcher = watcher::Watcher::new(api, &selector, policy).await?;
+            let balancer = Balancer::new(groups);
+            let remote = &servers[0].1.remote;
+
+            Forwarder::connect(members, options, resource, remote, &tracker, &token).await?;
 
             loop {
+                let accept =
+                    future::select_all(servers.iter().map(|(server, _)| Box::pin(server.accept())));
+
                 tokio::select! {
                     biased;
                     () = token.cancelled() => break,
                     // Wait for next pod before accepting new connections
-                    _ = watcher.next(), if watcher.is_empty() => {},
-                    Ok((connection, addr)) = server.accept() => {
-                        let api = api_ptr.clone();
+                    _ = balancer.next(), if balancer.is_empty() => {},
+                    (Ok(connection), index, _) = accept => {
+                        let Some(upstream) = balancer.pick() else {
+                            warn!("No pods available for {}", resource.alias);
+                            continue;
+                        };
+
+                        tracker.spawn(Forwarder::route(
+                            upstream,
+                            resource,
+                            &servers[index].1.remote,
+                            connection,
+                            token.child_token(),
+                        ));
+                    }
+                }
+            }
+
+            drop(ltoken);
+
+            Ok(())
+        };
+
+        Ok(future)
+    }
+
+    #[instrument(err, skip(self, sockets, resource, ltoken), fields(resource = %resource.alias))]
+    pub async fn bind_udp<'fut>(
+        &self,
+        sockets: Vec<UdpSocket>,
+        resource: &'static Resource,
+        ltoken: Option<LoopbackToken>,
+    ) -> Result<impl Future<Output = Result<()>> + 'fut> {
+        let token = self.token.child_token();
+        let tracker = self.tracker.clone();
 
-                        let Some(pod) = watcher.get() else { continue };
+        let options = watcher::Options::from(resource);
+        let (groups, members) = self.groups(resource).await?;
 
-                        let pod_name = pod.name_any();
-                        let pod_port = resource.ports.remote;
+        let servers = sockets
+            .into_iter()
+            .zip(resource.ports.mappings())
+            .map(|(socket, mapping)| {
+                info!(
+                    "Listening UDP on {} forwarded to {}:{}",
+                    socket.local_addr()?,
+                    resource.alias,
+                    mapping.remote
+                );
+
+                Ok((Arc::new(socket), mapping))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if servers.is_empty() {
+            anyhow::bail!("Resource {} has no ports", resource.alias);
+        }
+
+        Forwarder::check_policy(resource, &options, servers[0].0.local_addr()?);
+
+        let future = async move {
+            let balancer = Balancer::new(groups);
+            let remote = &servers[0].1.remote;
 
-                        info!(
-                            "Forwarding connection from {} to {}",
-                            addr,
-                            pod_name
-                        );
+            Forwarder::connect(members, options, resource, remote, &tracker, &token).await?;
 
-                        tracker.spawn(Forwarder::upstream(api, pod_port, pod_name, connection, token.child_token()));
+            let mut flows = Flows::default();
+            let mut buffer = vec![0; datagram::MAX_LENGTH];
+
+            loop {
+                let readable = future::select_all(
+                    servers
+                        .iter()
+                        .map(|(socket, _)| Box::pin(socket.readable())),
+                );
+
+                tokio::select! {
+                    biased;
+                    () = token.cancelled() => break,
+                    // Wait for next pod before reading new datagrams
+                    _ = balancer.next(), if balancer.is_empty() => {},
+                    (Ok(()), index, _) = readable => {
+                        let (socket, mapping) = &servers[index];
+
+                        let (length, addr) = match socket.try_recv_from(&mut buffer) {
+                            Ok(received) => received,
+                            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                            Err(e) => {
+                                warn!("Failed to receive datagram: {}", e);
+                                continue;
+                            }
+                        };
+
+                        let datagram = Bytes::copy_from_slice(&buffer[..length]);
+
+                        let Some(receiver) = flows.route((index, addr), datagram) else {
+                            continue;
+                        };
+
+                        let Some(upstream) = balancer.pick() else {
+                            warn!("No pods available for {}", resource.alias);
+                            continue;
+                        };
+
+                        tracker.spawn(Forwarder::flow(
+                            upstream,
+                            &mapping.remote,
+                            (socket.clone(), addr),
+                            receiver,
+                            token.child_token(),
+                        ));
                     }
                 }
             }
@@ -111,11 +248,167 @@ impl<'ctx> Forwarder<'ctx> {
         Ok(future)
     }
 
+    async fn groups(&self, resource: &'static Resource) -> Result<(Vec<Arc<Group>>, Vec<Member>)> {
+        let backends = resource.backends()?;
+
+        let mut groups = Vec::with_capacity(backends.len());
+        let mut members = Vec::new();
+
+        for backend in backends {
+            let contexts = match backend.contexts.as_slice() {
+                [] => vec![self.context],
+                contexts => contexts.iter().copied().map(Some).collect(),
+            };
+
+            let labels = contexts
+                .iter()
+                .map(|context| context.unwrap_or("default").to_owned())
+                .collect();
+
+            let group = Arc::new(Group::new(backend.weight, labels));
+
+            for (index, context) in contexts.into_iter().enumerate() {
+                let client = match context {
+                    Some(context) => self.pool.get_or_insert(context).await?,
+                    _ => self.pool.get_default().await?,
+                };
+
+                members.push(Member {
+                    group: group.clone(),
+                    index,
+                    client,
+                    backend: backend.clone(),
+                    protocol: resource.protocol.unwrap_or_default(),
+                    template: self.template,
+                });
+            }
+
+            groups.push(group);
+        }
+
+        Ok((groups, members))
+    }
+
+    #[instrument(err, skip(self, resource), fields(resource = %resource.alias))]
+    async fn proxy(&self, resource: &'static Resource, host: &str) -> Result<()> {
+        if resource.selector.is_some() || resource.backends.is_some() {
+            anyhow::bail!(
+                "Resource {} cannot have both a proxy and a selector",
+                resource.alias
+            );
+        }
+
+        if resource.contexts.is_some() {
+            anyhow::bail!("Proxy {} cannot fail over between contexts", resource.alias);
+        }
+
+        let client = match resource.context.as_deref().or(self.context) {
+            Some(context) => self.pool.get_or_insert(context).await?,
+            None => self.pool.get_default().await?,
+        };
+
+        let api = Arc::new(Api::<Pod>::namespaced(client, &resource.namespace));
+        let protocol = resource.protocol.unwrap_or_default();
+        let mappings = resource.ports.mappings();
+        let locals = mappings.iter().map(|mapping| mapping.local);
+
+        let (listeners, ltoken) = match protocol {
+            Protocol::Tcp => {
+                let (sockets, ltoken) = self.sockets.get_loopback(locals).await?;
+                let listeners = sockets
+                    .into_iter()
+                    .map(|socket| Ok(Listener::Tcp(socket.listen(1024)?)))
+                    .collect::<Result<Vec<_>>>()?;
+
+                (listeners, ltoken)
+            }
+            Protocol::Udp => {
+                let (sockets, ltoken) = self.sockets.get_loopback_udp(locals).await?;
+
+                (sockets.into_iter().map(Listener::Udp).collect(), ltoken)
+            }
+        };
+
+        let ltoken = Arc::new(ltoken);
+
+        // One proxy pod per port, each relaying to the same host
+        for (listener, mapping) in listeners.into_iter().zip(mappings) {
+            let RemotePort::Number(port) = mapping.remote else {
+                anyhow::bail!("Proxy {} needs numeric remote ports", resource.alias);
+            };
+
+            let target = ProxyTarget::new(host, port)
+                .with_context(|| format!("Invalid proxy host for {}", resource.alias))?;
+
+            info!(
+                "Listening {} on {} proxied to {}",
+                protocol,
+                listener.local_addr()?,
+                target
+            );
+
+            let destination = match protocol {
+                Protocol::Tcp => ProxyDestination::Tcp(target),
+                Protocol::Udp => ProxyDestination::Udp(target),
+            };
+
+            let proxy = Proxy::new(api.as_ref().clone()).with_template(self.template);
+            proxy.spawn(&destination).await?;
+
+            let serve = listener.serve(
+                api.clone(),
+                proxy.get_name(),
+                self.token.child_token(),
+                self.tracker.clone(),
+            );
+
+            let ltoken = ltoken.clone();
+
+            self.tracker.spawn(async move {
+                let exit = tokio::select! {
+                    exit = serve => exit,
+                    exit = proxy.wait_until_exit() => exit,
+                    exit = proxy.keep_alive() => exit,
+                };
+
+                if let Err(e) = exit {
+                    warn!("Stopped proxying {}: {}", resource.alias, e);
+                }
+
+                drop(ltoken);
+
+                proxy.abort().await
+            });
+        }
+
+        Ok(())
+    }
+
     pub async fn forward(&self, resource: &'static Resource) -> Result<()> {
-        let (socket, ltoken) = self.sockets.get_loopback(resource.ports.local).await?;
-        let future = self.bind(socket, resource, ltoken).await?;
+        if let Some(host) = &resource.proxy {
+            return self.proxy(resource, host).await;
+        }
+
+        let locals = resource
+            .ports
+            .mappings()
+            .iter()
+            .map(|mapping| mapping.local);
+
+        match resource.protocol.unwrap_or_default() {
+            Protocol::Tcp => {
+                let (sockets, ltoken) = self.sockets.get_loopback(locals).await?;
+                let future = self.bind(sockets, resource, ltoken).await?;
+
+                self.tracker.spawn(future);
+            }
+            Protocol::Udp => {
+                let (sockets, ltoken) = self.sockets.get_loopback_udp(locals).await?;
+                let future = self.bind_udp(sockets, resource, ltoken).await?;
 
-        self.tracker.spawn(future);
+                self.tracker.spawn(future);
+            }
+        }
 
         Ok(())
     }
@@ -139,36 +432,283 @@ impl<'ctx> Forwarder<'ctx> {
 }
 
 impl Forwarder<'_> {
+    // Local clients all connect from the loopback address of the listener, so
+    // hashing it sends every one of them to the same pod
+    fn check_policy(resource: &Resource, options: &watcher::Options, local: SocketAddr) {
+        if matches!(options.policy, SelectorPolicy::IpHash) && local.ip().is_loopback() {
+            warn!(
+                "Resource {} uses the iphash policy on the loopback address {}, every client will go to the same pod",
+                resource.alias,
+                local.ip()
+            );
+        }
+    }
+
+    async fn connect(
+        members: Vec<Member>,
+        options: watcher::Options,
+        resource: &'static Resource,
+        remote: &'static RemotePort,
+        tracker: &TaskTracker,
+        token: &CancellationToken,
+    ) -> Result<()> {
+        let connections =
+            future::join_all(members.iter().map(|member| member.connect(options.clone())));
+
+        for (member, upstream) in members.iter().zip(connections.await) {
+            match upstream {
+                Ok(upstream) => Forwarder::check(tracker, resource, remote, upstream, token),
+                Err(e) if member.group.len() == 1 => return Err(e),
+                Err(e) => warn!("Context {} is unavailable: {:#}", member.context(), e),
+            }
+        }
+
+        for member in members.into_iter().filter(|member| !member.is_connected()) {
+            tracker.spawn(Forwarder::reconnect(
+                member,
+                options.clone(),
+                resource,
+                remote,
+                tracker.clone(),
+                token.child_token(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn check(
+        tracker: &TaskTracker,
+        resource: &'static Resource,
+        remote: &'static RemotePort,
+        upstream: &Upstream,
+        token: &CancellationToken,
+    ) {
+        if let Some(relay) = &upstream.relay {
+            tracker.spawn(Forwarder::keep(relay.clone(), token.child_token()));
+        }
+
+        let Some(check) = &resource.health else {
+            return;
+        };
+
+        tracker.spawn(health::watch(
+            upstream.api.clone(),
+            upstream.watcher.clone(),
+            upstream.selection.clone(),
+            check,
+            remote,
+            token.child_token(),
+        ));
+    }
+
+    async fn keep(relay: Arc<Proxy>, token: CancellationToken) -> Result<()> {
+        let exit = tokio::select! {
+            biased;
+            () = token.cancelled() => Ok(()),
+            exit = relay.keep_alive() => exit,
+            exit = relay.wait_until_exit() => exit,
+        };
+
+        if let Err(e) = exit {
+            warn!("Relay stopped, datagrams are no longer forwarded: {}", e);
+        }
+
+        relay.abort().await
+    }
+
+    async fn reconnect(
+        member: Member,
+        options: watcher::Options,
+        resource: &'static Resource,
+        remote: &'static RemotePort,
+        tracker: TaskTracker,
+        token: CancellationToken,
+    ) {
+        loop {
+            tokio::select! {
+                biased;
+                () = token.cancelled() => break,
+                () = tokio::time::sleep(RECONNECT_INTERVAL) => {},
+            }
+
+            match member.connect(options.clone()).await {
+                Ok(upstream) => {
+                    info!("Context {} is available again", member.context());
+
+                    Forwarder::check(&tracker, resource, remote, upstream, &token);
+                    break;
+                }
+                Err(e) => debug!("Context {} is still unavailable: {:#}", member.context(), e),
+            }
+        }
+    }
+
+    #[instrument(err(level = Level::WARN), skip_all, fields(client = %addr))]
+    async fn route(
+        upstream: Upstream,
+        resource: &'static Resource,
+        remote: &'static RemotePort,
+        (connection, addr): (TcpStream, SocketAddr),
+        token: CancellationToken,
+    ) -> Result<()> {
+        let (tunnel, pod_name) = Forwarder::dial(&upstream, resource, remote, addr).await?;
+
+        info!("Forwarding connection from {} to {}", addr, pod_name);
+
+        let _guard = upstream.watcher.connections().acquire(pod_name);
+
+        Forwarder::relay(tunnel, connection, token).await
+    }
+
+    #[instrument(err(level = Level::WARN), skip_all, fields(client = %addr))]
+    async fn flow(
+        upstream: Upstream,
+        remote: &'static RemotePort,
+        (socket, addr): (Arc<UdpSocket>, SocketAddr),
+        receiver: mpsc::Receiver<Bytes>,
+        token: CancellationToken,
+    ) -> Result<()> {
+        let relay = upstream.relay.as_ref().context("No relay available")?;
+        let backend = upstream.watcher.get(addr).context("No pods available")?;
+        let pod_name = backend.name().to_owned();
+
+        let ip = backend
+            .ip()
+            .and_then(|ip| ip.parse().ok())
+            .with_context(|| format!("No address for {pod_name}"))?;
+
+        let port = ports::resolve(&backend, remote, &upstream.selection.ports, Protocol::Udp)
+            .with_context(|| format!("Cannot resolve port {remote} on {pod_name}"))?;
+
+        let tunnel = Forwarder::open(&upstream.api, Proxy::PORT, &relay.get_name()).await?;
+
+        info!(
+            "Forwarding datagrams from {} to {} through {}",
+            addr,
+            pod_name,
+            relay.get_name()
+        );
+
+        let _guard = upstream.watcher.connections().acquire(pod_name);
+        let target = SocketAddr::new(ip, port);
+
+        Forwarder::relay_datagrams(tunnel, Some(target), (socket, addr), receiver, token).await
+    }
+
+    async fn dial(
+        Upstream {
+            api,
+            watcher,
+            selection,
+            ..
+        }: &Upstream,
+        resource: &'static Resource,
+        remote: &'static RemotePort,
+        addr: SocketAddr,
+    ) -> Result<(Tunnel, String)> {
+        let retry = resource.retry.unwrap_or_default();
+        let penalty = Duration::from_secs(retry.penalty_seconds.unwrap_or(DEFAULT_PENALTY));
+        let grace = Duration::from_millis(retry.grace_milliseconds.unwrap_or(DEFAULT_GRACE));
+
+        let mut attempt = 0;
+
+        loop {
+            let backend = watcher.get(addr).context("No pods available")?;
+            let pod_name = backend.name().to_owned();
+
+            let tunnel = match ports::resolve(&backend, remote, &selection.ports, Protocol::Tcp) {
+                Some(pod_port) => Forwarder::open(api, pod_port, &pod_name).await,
+                None => Err(anyhow::anyhow!(
+                    "Cannot resolve port {remote} on {pod_name}"
+                )),
+            };
+
+            // A container not listening is only reported once the tunnel is open
+            let tunnel = match tunnel {
+                Ok(mut tunnel) if resource.retry.is_some() && !grace.is_zero() => {
+                    tunnel.settle(grace).await.map(|()| tunnel)
+                }
+                tunnel => tunnel,
+            };
+
+            let e = match tunnel {
+                Ok(tunnel) => return Ok((tunnel, pod_name)),
+                Err(e) => e,
+            };
+
+            if resource.retry.is_some() {
+                watcher.penalize(&backend, penalty);
+            }
+
+            if attempt >= retry.attempts {
+                return Err(e);
+            }
+
+            warn!("Retrying on another pod as {} failed: {:#}", pod_name, e);
+
+            attempt += 1;
+        }
+    }
+
     #[instrument(err(level = Level::WARN), skip(api, connection, token), fields(pod_name = %pod_name.as_ref()))]
     pub async fn upstream(
         api: Arc<Api<Pod>>,
         pod_port: u16,
         pod_name: impl AsRef<str>,
-        mut connection: TcpStream,
+        connection: TcpStream,
         token: CancellationToken,
     ) -> Result<()> {
-        // Optimization
-        connection.set_nodelay(true)?;
-        connection.set_linger(None)?;
+        let tunnel = Forwarder::open(&api, pod_port, pod_name.as_ref()).await?;
+
+        Forwarder::relay(tunnel, connection, token).await
+    }
+
+    #[instrument(err(level = Level::WARN), skip(api, socket, receiver, token), fields(pod_name = %pod_name.as_ref()))]
+    pub async fn upstream_datagrams(
+        api: Arc<Api<Pod>>,
+        pod_port: u16,
+        pod_name: impl AsRef<str>,
+        (socket, addr): (Arc<UdpSocket>, SocketAddr),
+        receiver: mpsc::Receiver<Bytes>,
+        token: CancellationToken,
+    ) -> Result<()> {
+        let tunnel = Forwarder::open(&api, pod_port, pod_name.as_ref()).await?;
 
-        debug!("Opening upstream connection to {}", pod_name.as_ref());
+        Forwarder::relay_datagrams(tunnel, None, (socket, addr), receiver, token).await
+    }
+
+    async fn open(api: &Api<Pod>, pod_port: u16, pod_name: &str) -> Result<Tunnel> {
+        debug!("Opening upstream connection to {}", pod_name);
 
         let ports = [pod_port];
-        let mut forwarding = api.portforward(pod_name.as_ref(), &ports).await?;
-        let mut upstream = forwarding
-            .take_stream(pod_port)
-            .context("Failed to take stream")?;
+        let forwarding = api.portforward(pod_name, &ports).await?;
 
-        let closer = forwarding
-            .take_error(pod_port)
-            .context("Failed to take error stream")?;
+        Tunnel::new(forwarding, pod_port)
+    }
+
+    async fn relay(
+        Tunnel {
+            forwarding,
+            mut upstream,
+            mut closer,
+            buffer,
+        }: Tunnel,
+        mut connection: TcpStream,
+        token: CancellationToken,
+    ) -> Result<()> {
+        // Optimization
+        connection.set_nodelay(true)?;
+        connection.set_linger(None)?;
 
         debug!("Upstream connection opened");
 
+        connection.write_all(&buffer).await?;
+
         tokio::select! {
             biased;
             () = token.cancelled() => {},
-            Some(e) = closer => {
+            Some(e) = &mut closer => {
                 forwarding.abort();
 
                 anyhow::bail!(e);
@@ -189,4 +729,71 @@ impl Forwarder<'_> {
             .await
             .context("Failed to conclude forward")
     }
+
+    async fn relay_datagrams(
+        Tunnel {
+            forwarding,
+            upstream,
+            mut closer,
+            buffer,
+        }: Tunnel,
+        target: Option<SocketAddr>,
+        (socket, addr): (Arc<UdpSocket>, SocketAddr),
+        mut receiver: mpsc::Receiver<Bytes>,
+        token: CancellationToken,
+    ) -> Result<()> {
+        let mut upstream = datagram::framed(upstream);
+        upstream.read_buffer_mut().extend_from_slice(&buffer);
+
+        // Relays without a fixed target expect it as the first frame
+        if let Some(target) = target {
+            upstream.send(Bytes::from(target.to_string())).await?;
+        }
+
+        let idle = tokio::time::sleep(FLOW_IDLE_TIMEOUT);
+        tokio::pin!(idle);
+
+        debug!("Upstream flow opened");
+
+        loop {
+            tokio::select! {
+                biased;
+                () = token.cancelled() => break,
+                () = &mut idle => {
+                    debug!("Flow from {} expired", addr);
+                    break;
+                }
+                Some(e) = &mut closer => {
+                    forwarding.abort();
+
+                    anyhow::bail!(e);
+                }
+                Some(datagram) = receiver.recv() => {
+                    upstream.send(datagram).await?;
+                }
+                frame = upstream.next() => match frame {
+                    Some(Ok(frame)) => {
+                        socket.send_to(&frame, addr).await?;
+                    }
+                    Some(Err(e)) => {
+                        forwarding.abort();
+
+                        anyhow::bail!(e);
+                    }
+                    None => break,
+                },
+            }
+
+            idle.as_mut().reset(Instant::now() + FLOW_IDLE_TIMEOUT);
+        }
+
+        debug!("Going to gracefully drop upstream flow");
+
+        drop(upstream);
+
+        forwarding
+            .join()
+            .await
+            .context("Failed to conclude forward")
+    }
 }
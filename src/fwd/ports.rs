@@ -0,0 +1,211 @@
+use k8s_openapi::{
+    api::core::v1::{Pod, ServicePort},
+    apimachinery::pkg::util::intstr::IntOrString,
+};
+
+use crate::{
+    cnf::schema::{Protocol, RemotePort},
+    fwd::watcher::Backend,
+};
+
+pub fn resolve(
+    backend: &Backend,
+    remote: &RemotePort,
+    service: &[ServicePort],
+    protocol: Protocol,
+) -> Option<u16> {
+    let protocol = protocol.to_string();
+    let mapping = service
+        .iter()
+        .filter(|port| port.protocol.as_deref().unwrap_or("TCP") == protocol)
+        .find(|port| match remote {
+            RemotePort::Number(number) => port.port == i32::from(*number),
+            RemotePort::Name(name) => port.name.as_ref() == Some(name),
+        });
+
+    // Ports not exposed by the service are dialed on the pod as they are
+    match (backend, mapping, remote) {
+        (_, None, RemotePort::Number(number)) => Some(*number),
+        (Backend::Pod(pod), Some(port), _) => resolve_target(pod, port, &protocol),
+        (Backend::Pod(pod), None, RemotePort::Name(name)) => resolve_named(pod, name, &protocol),
+        // Slices already carry the resolved target port of every service port
+        (Backend::Endpoint(slice, _), Some(port), _) => slice
+            .ports
+            .iter()
+            .flatten()
+            .find(|endpoint| endpoint.name == port.name)
+            .and_then(|endpoint| endpoint.port)
+            .and_then(|port| u16::try_from(port).ok()),
+        (Backend::Endpoint(..), None, RemotePort::Name(_)) => None,
+    }
+}
+
+fn resolve_target(pod: &Pod, port: &ServicePort, protocol: &str) -> Option<u16> {
+    match &port.target_port {
+        Some(IntOrString::Int(target)) => u16::try_from(*target).ok(),
+        Some(IntOrString::String(name)) => resolve_named(pod, name, protocol),
+        None => u16::try_from(port.port).ok(),
+    }
+}
+
+fn resolve_named(pod: &Pod, name: &str, protocol: &str) -> Option<u16> {
+    pod.spec
+        .as_ref()?
+        .containers
+        .iter()
+        .flat_map(|container| container.ports.iter().flatten())
+        .filter(|port| port.protocol.as_deref().unwrap_or("TCP") == protocol)
+        .find(|port| port.name.as_deref() == Some(name))
+        .and_then(|port| u16::try_from(port.container_port).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use k8s_openapi::api::{
+        core::v1::{Container, ContainerPort, PodSpec},
+        discovery::v1::{Endpoint, EndpointPort, EndpointSlice},
+    };
+
+    use super::*;
+
+    fn pod() -> Backend {
+        let port = |name: &str, container_port, protocol: &str| ContainerPort {
+            name: Some(name.to_string()),
+            container_port,
+            protocol: Some(protocol.to_string()),
+            ..Default::default()
+        };
+
+        Backend::Pod(Arc::new(Pod {
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    ports: Some(vec![
+                        port("http", 8080, "TCP"),
+                        port("metrics", 9090, "TCP"),
+                        port("dns", 5353, "UDP"),
+                    ]),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }))
+    }
+
+    fn service() -> Vec<ServicePort> {
+        vec![
+            ServicePort {
+                name: Some("web".to_string()),
+                port: 80,
+                target_port: Some(IntOrString::String("http".to_string())),
+                ..Default::default()
+            },
+            ServicePort {
+                name: Some("https".to_string()),
+                port: 443,
+                target_port: Some(IntOrString::Int(8443)),
+                protocol: Some("TCP".to_string()),
+                ..Default::default()
+            },
+            ServicePort {
+                name: Some("dns".to_string()),
+                port: 53,
+                target_port: Some(IntOrString::String("dns".to_string())),
+                protocol: Some("UDP".to_string()),
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn resolves_numeric_target() {
+        let remote = RemotePort::Number(443);
+        assert_eq!(
+            resolve(&pod(), &remote, &service(), Protocol::Tcp),
+            Some(8443)
+        );
+
+        let remote = RemotePort::Name("https".to_string());
+        assert_eq!(
+            resolve(&pod(), &remote, &service(), Protocol::Tcp),
+            Some(8443)
+        );
+    }
+
+    #[test]
+    fn resolves_named_target() {
+        let remote = RemotePort::Number(80);
+        assert_eq!(
+            resolve(&pod(), &remote, &service(), Protocol::Tcp),
+            Some(8080)
+        );
+
+        // Without a service, names are looked up in the container ports
+        let remote = RemotePort::Name("metrics".to_string());
+        assert_eq!(resolve(&pod(), &remote, &[], Protocol::Tcp), Some(9090));
+    }
+
+    #[test]
+    fn dials_ports_not_exposed_by_the_service() {
+        let remote = RemotePort::Number(9090);
+        assert_eq!(
+            resolve(&pod(), &remote, &service(), Protocol::Tcp),
+            Some(9090)
+        );
+
+        let remote = RemotePort::Name("missing".to_string());
+        assert_eq!(resolve(&pod(), &remote, &service(), Protocol::Tcp), None);
+    }
+
+    #[test]
+    fn matches_the_protocol() {
+        let remote = RemotePort::Name("dns".to_string());
+        assert_eq!(
+            resolve(&pod(), &remote, &service(), Protocol::Udp),
+            Some(5353)
+        );
+        assert_eq!(resolve(&pod(), &remote, &service(), Protocol::Tcp), None);
+
+        // A TCP service port is not translated for UDP
+        let remote = RemotePort::Number(80);
+        assert_eq!(
+            resolve(&pod(), &remote, &service(), Protocol::Udp),
+            Some(80)
+        );
+    }
+
+    #[test]
+    fn resolves_from_endpoint_slices() {
+        let slice = EndpointSlice {
+            address_type: "IPv4".to_string(),
+            endpoints: vec![Endpoint {
+                addresses: vec!["10.0.0.1".to_string()],
+                ..Default::default()
+            }],
+            ports: Some(vec![EndpointPort {
+                name: Some("web".to_string()),
+                port: Some(8081),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+        let backend = Backend::Endpoint(Arc::new(slice), 0);
+
+        let remote = RemotePort::Number(80);
+        assert_eq!(
+            resolve(&backend, &remote, &service(), Protocol::Tcp),
+            Some(8081)
+        );
+
+        let remote = RemotePort::Number(8080);
+        assert_eq!(
+            resolve(&backend, &remote, &service(), Protocol::Tcp),
+            Some(8080)
+        );
+
+        let remote = RemotePort::Name("metrics".to_string());
+        assert_eq!(resolve(&backend, &remote, &service(), Protocol::Tcp), None);
+    }
+}
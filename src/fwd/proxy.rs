@@ -1,41 +1,150 @@
 use std::{
     collections::BTreeMap,
-    net::SocketAddr,
-    sync::atomic::{AtomicBool, Ordering},
+    env,
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Result;
-use futures::TryStreamExt;
-use k8s_openapi::api::core::v1::{Container, Pod, PodSpec};
+use anyhow::{Context, Result};
+use futures::{TryStreamExt, future};
+use k8s_openapi::{
+    api::{
+        authorization::v1::{
+            ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+        },
+        core::v1::{
+            Capabilities, Container, DownwardAPIVolumeFile, DownwardAPIVolumeSource,
+            LocalObjectReference, ObjectFieldSelector, Pod, PodSpec, ResourceRequirements,
+            SeccompProfile, SecurityContext, Toleration, Volume, VolumeMount,
+        },
+    },
+    apimachinery::pkg::api::resource::Quantity,
+};
 use kube::{
     Api,
-    api::{DeleteParams, ObjectMeta, PostParams, WatchEvent, WatchParams},
+    api::{DeleteParams, ObjectMeta, Patch, PatchParams, PostParams},
+    runtime::{WatchStreamExt, watcher},
 };
 use nanoid::nanoid;
-use tracing::{debug, instrument};
+use serde_json::json;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    time::MissedTickBehavior,
+};
+use tokio_util::{bytes::Bytes, sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, instrument, warn};
+
+use crate::{
+    cnf::schema::{ProxySecurityContext, ProxyTemplate, ProxyToleration},
+    fwd::{
+        Forwarder,
+        datagram::{self, Flows},
+    },
+};
 
 static ALPHABET: [char; 16] = [
     '1', '2', '3', '4', '5', '6', '7', '8', '9', '0', 'a', 'b', 'c', 'd', 'e', 'f',
 ];
 
-pub enum ProxyDestination {
-    Tcp(SocketAddr),
-    Udp(SocketAddr),
+/// A `host:port` pair, where the host is resolved from within the proxy pod
+#[derive(Clone, Debug)]
+pub struct ProxyTarget {
+    pub host: String,
+    pub port: u16,
 }
 
-impl ProxyDestination {
-    pub fn to_socat_target(&self) -> String {
-        match self {
-            ProxyDestination::Tcp(target) => format!("TCP:{target}"),
-            ProxyDestination::Udp(target) => format!("UDP:{target}"),
+impl ProxyTarget {
+    // The host ends up in the socat address, so anything but a hostname or an
+    // IP literal could smuggle in socat options
+    pub fn new(host: &str, port: u16) -> Result<Self> {
+        if host.is_empty() {
+            anyhow::bail!("Target host cannot be empty");
+        }
+
+        if host.parse::<IpAddr>().is_err() && !Self::is_hostname(host) {
+            anyhow::bail!("Target host {host} is neither a hostname nor an IP address");
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    fn is_hostname(host: &str) -> bool {
+        host.len() <= 253
+            && host.split('.').all(|label| {
+                (1..=63).contains(&label.len())
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            })
+    }
+}
+
+impl FromStr for ProxyTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(target: &str) -> Result<Self> {
+        if let Ok(address) = target.parse::<SocketAddr>() {
+            return Ok(Self {
+                host: address.ip().to_string(),
+                port: address.port(),
+            });
+        }
+
+        let (host, port) = target
+            .rsplit_once(':')
+            .context("Target must be in the form host:port")?;
+
+        let port = port.parse().context("Invalid target port")?;
+
+        // IPv6 addresses must be bracketed to tell them apart from the port
+        match host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+        {
+            Some(host) if host.parse::<Ipv6Addr>().is_ok() => Self::new(host, port),
+            Some("") => anyhow::bail!("Target host cannot be empty"),
+            Some(host) => anyhow::bail!("Target host [{host}] is not an IPv6 address"),
+            None if host.contains(':') => {
+                anyhow::bail!("Target host {host} must be bracketed, as in [{host}]:{port}")
+            }
+            None => Self::new(host, port),
+        }
+    }
+}
+
+impl Display for ProxyTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
         }
     }
 }
 
+pub enum ProxyDestination {
+    Tcp(ProxyTarget),
+    Udp(ProxyTarget),
+    // Relays datagrams to the target named at the start of every tunnel connection
+    Relay,
+}
+
 pub struct Proxy {
     id: String,
     api: Api<Pod>,
     permit: AtomicBool,
+    heartbeat: AtomicBool,
+    template: Option<&'static ProxyTemplate>,
+    timeout: Option<Duration>,
 }
 
 impl Proxy {
@@ -44,16 +153,49 @@ impl Proxy {
     const IMAGE: &str = "alpine/socat:latest";
     const IMAGE_BIN: &str = "socat";
 
+    // socat cannot preserve datagram boundaries over a stream, so UDP goes through a relay script
+    const RELAY_IMAGE: &str = "python:3-alpine";
+    const RELAY_BIN: &str = "python3";
+    const RELAY_SCRIPT: &str = include_str!("relay.py");
+
+    const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
     pub const PORT: u16 = 8080;
 
+    pub const SELECTOR: &str = "kubef.io/proxy=true";
+    pub const OWNER: &str = "kubef.io/owner";
+
+    // The pod stops by itself once kubef has not renewed the heartbeat for a while
+    pub const HEARTBEAT: &str = "kubef.io/heartbeat";
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+    const EXPIRE_AFTER: u64 = 300;
+    // The kubelet only refreshes downward API volumes every minute or so
+    const MIN_EXPIRE_AFTER: u64 = 120;
+    const WATCHDOG_BIN: &str = "sh";
+    const WATCHDOG_SCRIPT: &str = include_str!("watchdog.sh");
+    const WATCHDOG_PATH: &str = "/etc/kubef";
+
     pub fn new(api: Api<Pod>) -> Self {
         Self {
             id: nanoid!(6, &ALPHABET),
             api,
             permit: AtomicBool::new(false),
+            heartbeat: AtomicBool::new(false),
+            template: None,
+            timeout: None,
         }
     }
 
+    pub fn with_timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.timeout = timeout.into();
+        self
+    }
+
+    pub fn with_template(mut self, template: impl Into<Option<&'static ProxyTemplate>>) -> Self {
+        self.template = template.into();
+        self
+    }
+
     #[inline]
     pub fn is_spawned(&self) -> bool {
         self.permit.load(Ordering::Relaxed)
@@ -78,26 +220,93 @@ impl Proxy {
         Ok(())
     }
 
+    // Only returns once the heartbeat cannot be renewed anymore, either
+    // because the pod is gone or terminated or because patching is forbidden
+    pub async fn keep_alive(&self) -> Result<()> {
+        if !self.heartbeat.load(Ordering::Relaxed) {
+            return future::pending().await;
+        }
+
+        let mut interval = tokio::time::interval(Self::HEARTBEAT_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            match self.renew().await {
+                Ok(pod) => {
+                    if let Some(reason) = Self::terminated(&pod) {
+                        anyhow::bail!("Proxy pod {} {}", self.get_name(), reason);
+                    }
+
+                    debug!("Proxy {} heartbeat renewed", self.id);
+                }
+                Err(kube::Error::Api(response)) if matches!(response.code, 403 | 404) => {
+                    return Err(self.renewal_error(kube::Error::Api(response)));
+                }
+                Err(e) => warn!("Failed to renew heartbeat of proxy {}: {}", self.id, e),
+            }
+        }
+    }
+
+    // Only returns once the pod is deleted or has terminated, with the reason
     pub async fn wait_until_exit(&self) -> Result<()> {
         if !self.is_spawned() {
             return Err(anyhow::anyhow!("Proxy not spawned"));
         }
 
-        let params = WatchParams::default().labels(&format!("kubef.io/id={}", self.id));
-        let stream = self.api.watch_metadata(&params, "0").await?;
+        let config =
+            watcher::Config::default().fields(&format!("metadata.name={}", self.get_name()));
+        let stream = watcher(self.api.clone(), config).default_backoff();
 
         tokio::pin!(stream);
 
-        while let Ok(Some(event)) = stream.try_next().await {
+        let mut seen = false;
+
+        while let Some(event) = stream.try_next().await? {
             match event {
-                WatchEvent::Error(_) | WatchEvent::Deleted(_) => return Ok(()),
-                _ => {
-                    debug!("Proxy {} received event: {:?}", self.id, event);
+                watcher::Event::Apply(pod) | watcher::Event::InitApply(pod) => {
+                    seen = true;
+
+                    if let Some(reason) = Self::terminated(&pod) {
+                        anyhow::bail!("Proxy pod {} {}", self.get_name(), reason);
+                    }
                 }
+                watcher::Event::Delete(_) => break,
+                watcher::Event::Init => seen = false,
+                // The pod was deleted while the watch was being re-established
+                watcher::Event::InitDone if !seen => break,
+                watcher::Event::InitDone => {}
             }
         }
 
-        Ok(())
+        anyhow::bail!("Proxy pod {} was deleted", self.get_name())
+    }
+
+    async fn renew(&self) -> kube::Result<Pod> {
+        let patch = json!({ "metadata": { "annotations": { Self::HEARTBEAT: Self::now() } } });
+
+        self.api
+            .patch(
+                &self.get_name(),
+                &PatchParams::default(),
+                &Patch::Merge(&patch),
+            )
+            .await
+    }
+
+    fn renewal_error(&self, error: kube::Error) -> anyhow::Error {
+        match error {
+            kube::Error::Api(response) if response.code == 403 => anyhow::anyhow!(
+                "Cannot renew the heartbeat of proxy pod {}, which requires permission to patch pods: {}",
+                self.get_name(),
+                response.message
+            ),
+            kube::Error::Api(response) if response.code == 404 => {
+                anyhow::anyhow!("Proxy pod {} was deleted", self.get_name())
+            }
+            e => e.into(),
+        }
     }
 
     #[instrument(skip(self, destination))]
@@ -106,35 +315,409 @@ impl Proxy {
             return Err(anyhow::anyhow!("Proxy already spawned"));
         }
 
-        let source = format!("TCP-LISTEN:{},reuseaddr,fork", Self::PORT);
-        let destination = destination.to_socat_target();
+        let template = self.template.cloned().unwrap_or_default();
+
+        let expire_after = template.expire_after_seconds.unwrap_or(Self::EXPIRE_AFTER);
+        if expire_after < Self::MIN_EXPIRE_AFTER {
+            anyhow::bail!(
+                "Proxy expire_after_seconds must be at least {}s, as the kubelet only refreshes the heartbeat periodically",
+                Self::MIN_EXPIRE_AFTER
+            );
+        }
+
+        // A watchdog that never sees the heartbeat change would stop the pod
+        // while in use, so only run it when kubef is allowed to renew it
+        let watchdog = template.watchdog.unwrap_or(true) && self.can_renew().await;
+        self.heartbeat.store(watchdog, Ordering::Relaxed);
+
+        let pod = self.build(template, destination, watchdog.then_some(expire_after));
+
+        self.api
+            .create(&PostParams::default(), &pod)
+            .await
+            .map_err(|e| match e {
+                kube::Error::Api(response) => anyhow::anyhow!(
+                    "Proxy pod was rejected by the cluster: {}",
+                    response.message
+                ),
+                e => e.into(),
+            })?;
+
+        self.permit.store(true, Ordering::Relaxed);
+
+        let timeout = self
+            .timeout
+            .or_else(|| {
+                self.template
+                    .and_then(|template| template.ready_timeout_seconds)
+                    .map(Duration::from_secs)
+            })
+            .unwrap_or(Self::READY_TIMEOUT);
+
+        if let Err(e) = self.wait_until_ready(timeout).await {
+            self.abort().await?;
+
+            return Err(e);
+        }
+
+        // Without the heartbeat the pod would stop by itself, so fail now
+        // rather than once it expires
+        if watchdog {
+            if let Err(e) = self.renew().await {
+                self.abort().await?;
+
+                return Err(self.renewal_error(e));
+            }
+        }
+
+        Ok(())
+    }
 
-        // TODO: Can we improve this?
-        let pod = Pod {
+    async fn can_renew(&self) -> bool {
+        let api = Api::<SelfSubjectAccessReview>::all(self.api.clone().into_client());
+        let review = SelfSubjectAccessReview {
+            spec: SelfSubjectAccessReviewSpec {
+                resource_attributes: Some(ResourceAttributes {
+                    namespace: self.api.namespace().map(str::to_string),
+                    verb: Some("patch".to_string()),
+                    resource: Some("pods".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        match api.create(&PostParams::default(), &review).await {
+            Ok(review) if review.status.as_ref().is_some_and(|status| status.allowed) => true,
+            Ok(_) => {
+                warn!(
+                    "Not allowed to patch pods, proxy {} will not stop by itself if kubef is killed",
+                    self.id
+                );
+
+                false
+            }
+            // Let the first renewal tell whether patching is allowed
+            Err(e) => {
+                debug!("Cannot review access to patch pods: {}", e);
+
+                true
+            }
+        }
+    }
+
+    fn command(template: &ProxyTemplate, destination: &ProxyDestination) -> (String, Vec<String>) {
+        match destination {
+            ProxyDestination::Tcp(target) => (
+                template
+                    .image
+                    .clone()
+                    .unwrap_or_else(|| Self::IMAGE.to_string()),
+                vec![
+                    Self::IMAGE_BIN.to_string(),
+                    format!("TCP-LISTEN:{},reuseaddr,fork", Self::PORT),
+                    format!("TCP:{target}"),
+                ],
+            ),
+            ProxyDestination::Udp(target) => (
+                template
+                    .relay_image
+                    .clone()
+                    .unwrap_or_else(|| Self::RELAY_IMAGE.to_string()),
+                vec![
+                    Self::RELAY_BIN.to_string(),
+                    "-c".to_string(),
+                    Self::RELAY_SCRIPT.to_string(),
+                    Self::PORT.to_string(),
+                    target.host.clone(),
+                    target.port.to_string(),
+                ],
+            ),
+            ProxyDestination::Relay => (
+                template
+                    .relay_image
+                    .clone()
+                    .unwrap_or_else(|| Self::RELAY_IMAGE.to_string()),
+                vec![
+                    Self::RELAY_BIN.to_string(),
+                    "-c".to_string(),
+                    Self::RELAY_SCRIPT.to_string(),
+                    Self::PORT.to_string(),
+                ],
+            ),
+        }
+    }
+
+    fn build(
+        &self,
+        template: ProxyTemplate,
+        destination: &ProxyDestination,
+        expire_after: Option<u64>,
+    ) -> Pod {
+        let (image, command) = Self::command(&template, destination);
+
+        let command = match expire_after {
+            Some(expire_after) => [
+                Self::WATCHDOG_BIN.to_string(),
+                "-c".to_string(),
+                Self::WATCHDOG_SCRIPT.to_string(),
+                "watchdog".to_string(),
+                expire_after.to_string(),
+            ]
+            .into_iter()
+            .chain(command)
+            .collect(),
+            None => command,
+        };
+
+        // Our own labels always win, as they are how kubef finds its pods again
+        let mut labels = template.labels.unwrap_or_default();
+        labels.insert("kubef.io/id".to_string(), self.id.clone());
+        labels.insert("kubef.io/proxy".to_string(), "true".to_string());
+
+        let mut annotations = template.annotations.unwrap_or_default();
+        annotations.insert(Self::OWNER.to_string(), Self::owner());
+        if expire_after.is_some() {
+            annotations.insert(Self::HEARTBEAT.to_string(), Self::now());
+        }
+
+        Pod {
             metadata: ObjectMeta {
                 name: Some(self.get_name()),
-                labels: Some(BTreeMap::from([
-                    ("kubef.io/id".to_string(), self.id.clone()),
-                    ("kubef.io/proxy".to_string(), "true".to_string()),
-                ])),
+                labels: Some(labels),
+                annotations: Some(annotations),
                 ..Default::default()
             },
             spec: Some(PodSpec {
                 containers: vec![Container {
                     name: "socat".to_string(),
-                    image: Some(Self::IMAGE.to_string()),
-                    command: Some(vec![Self::IMAGE_BIN.to_string(), source, destination]),
+                    image: Some(image),
+                    volume_mounts: expire_after.map(|_| {
+                        vec![VolumeMount {
+                            name: "kubef".to_string(),
+                            mount_path: Self::WATCHDOG_PATH.to_string(),
+                            read_only: Some(true),
+                            ..Default::default()
+                        }]
+                    }),
+                    command: Some(command),
+                    resources: template.resources.map(|resources| ResourceRequirements {
+                        requests: resources.requests.map(Self::quantities),
+                        limits: resources.limits.map(Self::quantities),
+                        ..Default::default()
+                    }),
+                    security_context: template.security_context.map(Self::security_context),
                     ..Default::default()
                 }],
+                volumes: expire_after.map(|_| vec![Self::annotations_volume()]),
+                active_deadline_seconds: template.active_deadline_seconds,
+                // An expired proxy must not be brought back by the kubelet
+                restart_policy: Some("Never".to_string()),
+                image_pull_secrets: template.image_pull_secrets.map(|secrets| {
+                    secrets
+                        .into_iter()
+                        .map(|name| LocalObjectReference { name })
+                        .collect()
+                }),
+                service_account_name: template.service_account,
+                node_selector: template.node_selector,
+                tolerations: template
+                    .tolerations
+                    .map(|tolerations| tolerations.into_iter().map(Self::toleration).collect()),
                 ..Default::default()
             }),
             status: None,
+        }
+    }
+
+    async fn wait_until_ready(&self, timeout: Duration) -> Result<()> {
+        let mut pending = None;
+
+        match tokio::time::timeout(timeout, self.watch_ready(&mut pending)).await {
+            Ok(ready) => ready,
+            Err(_) => match pending {
+                Some(reason) => anyhow::bail!(
+                    "Proxy pod {} was not ready after {}s: {}",
+                    self.get_name(),
+                    timeout.as_secs(),
+                    reason
+                ),
+                None => anyhow::bail!(
+                    "Proxy pod {} was not ready after {}s",
+                    self.get_name(),
+                    timeout.as_secs()
+                ),
+            },
+        }
+    }
+
+    async fn watch_ready(&self, pending: &mut Option<String>) -> Result<()> {
+        let config =
+            watcher::Config::default().fields(&format!("metadata.name={}", self.get_name()));
+        let stream = watcher(self.api.clone(), config).applied_objects();
+
+        tokio::pin!(stream);
+
+        while let Some(pod) = stream.try_next().await? {
+            if Self::is_ready(&pod, pending)? {
+                debug!("Proxy {} is ready", self.id);
+
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("Proxy pod {} disappeared", self.get_name())
+    }
+
+    // Fails fast on states that will not resolve by themselves, and keeps the
+    // reason of transient ones (such as scheduling) to report it on timeout
+    fn is_ready(pod: &Pod, pending: &mut Option<String>) -> Result<bool> {
+        let Some(status) = &pod.status else {
+            return Ok(false);
         };
 
-        self.api.create(&PostParams::default(), &pod).await?;
-        self.permit.store(true, Ordering::Relaxed);
+        if let Some(reason) = Self::terminated(pod) {
+            anyhow::bail!("Proxy pod {reason}");
+        }
 
-        Ok(())
+        // The relay listens as soon as it starts, so a running container is
+        // ready without probing it, which would dial the target every time
+        let running = status.container_statuses.iter().flatten().any(|container| {
+            container
+                .state
+                .as_ref()
+                .is_some_and(|state| state.running.is_some())
+        });
+
+        if running {
+            return Ok(true);
+        }
+
+        for condition in status.conditions.iter().flatten() {
+            if condition.type_ == "PodScheduled" && condition.status == "False" {
+                *pending = Some(format!(
+                    "{}: {}",
+                    condition.reason.as_deref().unwrap_or("Unschedulable"),
+                    condition.message.as_deref().unwrap_or("no message")
+                ));
+            }
+        }
+
+        let waiting = status
+            .container_statuses
+            .iter()
+            .flatten()
+            .filter_map(|container| container.state.as_ref()?.waiting.as_ref());
+
+        for state in waiting {
+            let reason = state.reason.as_deref().unwrap_or_default();
+            let message = state.message.as_deref().unwrap_or("no message");
+
+            match reason {
+                "ImagePullBackOff"
+                | "InvalidImageName"
+                | "CreateContainerConfigError"
+                | "CrashLoopBackOff" => {
+                    anyhow::bail!("Proxy container is stuck in {reason}: {message}")
+                }
+                "" | "ContainerCreating" => {}
+                _ => *pending = Some(format!("{reason}: {message}")),
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+impl Proxy {
+    fn terminated(pod: &Pod) -> Option<String> {
+        let status = pod.status.as_ref()?;
+
+        match status.phase.as_deref() {
+            Some(phase @ ("Failed" | "Succeeded")) => Some(format!(
+                "terminated with phase {}: {}",
+                phase,
+                status.message.as_deref().unwrap_or("no message")
+            )),
+            _ => None,
+        }
+    }
+
+    fn now() -> String {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string()
+    }
+
+    // Tells whose proxy pod this is when cleaning up after a crash
+    fn owner() -> String {
+        let user = env::var("USER")
+            .or_else(|_| env::var("USERNAME"))
+            .unwrap_or_else(|_| "unknown".to_string());
+
+        // HOSTNAME is a shell variable that is rarely exported, so ask the OS
+        match hostname::get() {
+            Ok(host) => format!("{user}@{}", host.to_string_lossy()),
+            Err(_) => user,
+        }
+    }
+
+    // Exposes the heartbeat to the watchdog without giving the pod API access
+    fn annotations_volume() -> Volume {
+        Volume {
+            name: "kubef".to_string(),
+            downward_api: Some(DownwardAPIVolumeSource {
+                items: Some(vec![DownwardAPIVolumeFile {
+                    path: "annotations".to_string(),
+                    field_ref: Some(ObjectFieldSelector {
+                        field_path: "metadata.annotations".to_string(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn toleration(toleration: ProxyToleration) -> Toleration {
+        Toleration {
+            key: toleration.key,
+            operator: toleration.operator,
+            value: toleration.value,
+            effect: toleration.effect,
+            toleration_seconds: toleration.toleration_seconds,
+        }
+    }
+
+    fn quantities(quantities: BTreeMap<String, String>) -> BTreeMap<String, Quantity> {
+        quantities
+            .into_iter()
+            .map(|(name, quantity)| (name, Quantity(quantity)))
+            .collect()
+    }
+
+    fn security_context(context: ProxySecurityContext) -> SecurityContext {
+        SecurityContext {
+            run_as_non_root: context.run_as_non_root,
+            run_as_user: context.run_as_user,
+            run_as_group: context.run_as_group,
+            read_only_root_filesystem: context.read_only_root_filesystem,
+            allow_privilege_escalation: context.allow_privilege_escalation,
+            capabilities: context.drop_capabilities.map(|drop| Capabilities {
+                drop: Some(drop),
+                ..Default::default()
+            }),
+            seccomp_profile: context.seccomp_profile.map(|type_| SeccompProfile {
+                type_,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
     }
 }
 
@@ -150,3 +733,142 @@ impl Drop for Proxy {
         tokio::spawn(async move { api.delete(&name, &DeleteParams::default()).await });
     }
 }
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl Listener {
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(socket) => socket.local_addr(),
+            Listener::Udp(socket) => socket.local_addr(),
+        }
+    }
+
+    pub async fn serve(
+        self,
+        api: Arc<Api<Pod>>,
+        name: String,
+        token: CancellationToken,
+        tracker: TaskTracker,
+    ) -> Result<()> {
+        match self {
+            Listener::Tcp(socket) => accept(api, name, socket, token, tracker).await,
+            Listener::Udp(socket) => receive(api, name, socket, token, tracker).await,
+        }
+    }
+}
+
+async fn accept(
+    api: Arc<Api<Pod>>,
+    name: String,
+    socket: TcpListener,
+    token: CancellationToken,
+    tracker: TaskTracker,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            biased;
+            () = token.cancelled() => break,
+            Ok((connection, _)) = socket.accept() => {
+                let api = api.clone();
+                let token = token.child_token();
+
+                tracker.spawn(Forwarder::upstream(api, Proxy::PORT, name.clone(), connection, token));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn receive(
+    api: Arc<Api<Pod>>,
+    name: String,
+    socket: UdpSocket,
+    token: CancellationToken,
+    tracker: TaskTracker,
+) -> Result<()> {
+    let socket = Arc::new(socket);
+
+    let mut flows = Flows::default();
+    let mut buffer = vec![0; datagram::MAX_LENGTH];
+
+    loop {
+        tokio::select! {
+            biased;
+            () = token.cancelled() => break,
+            Ok((length, addr)) = socket.recv_from(&mut buffer) => {
+                let datagram = Bytes::copy_from_slice(&buffer[..length]);
+
+                let Some(receiver) = flows.route(addr, datagram) else {
+                    continue;
+                };
+
+                let api = api.clone();
+                let token = token.child_token();
+
+                tracker.spawn(Forwarder::upstream_datagrams(
+                    api,
+                    Proxy::PORT,
+                    name.clone(),
+                    (socket.clone(), addr),
+                    receiver,
+                    token,
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hostnames_and_ips() {
+        let target: ProxyTarget = "db.internal:5432".parse().unwrap();
+        assert_eq!((target.host.as_str(), target.port), ("db.internal", 5432));
+
+        let target: ProxyTarget = "10.0.0.1:80".parse().unwrap();
+        assert_eq!((target.host.as_str(), target.port), ("10.0.0.1", 80));
+    }
+
+    #[test]
+    fn parses_bracketed_ipv6() {
+        let target: ProxyTarget = "[fd00::1]:53".parse().unwrap();
+        assert_eq!((target.host.as_str(), target.port), ("fd00::1", 53));
+        assert_eq!(target.to_string(), "[fd00::1]:53");
+
+        assert!("fd00::1:53".parse::<ProxyTarget>().is_err());
+        assert!("[db.internal]:53".parse::<ProxyTarget>().is_err());
+    }
+
+    #[test]
+    fn rejects_missing_port() {
+        assert!("db.internal".parse::<ProxyTarget>().is_err());
+        assert!("db.internal:".parse::<ProxyTarget>().is_err());
+        assert!("db.internal:http".parse::<ProxyTarget>().is_err());
+        assert!("[fd00::1]".parse::<ProxyTarget>().is_err());
+    }
+
+    #[test]
+    fn rejects_empty_host() {
+        assert!(":80".parse::<ProxyTarget>().is_err());
+        assert!("[]:80".parse::<ProxyTarget>().is_err());
+        assert!(ProxyTarget::new("", 80).is_err());
+    }
+
+    #[test]
+    fn rejects_socat_options() {
+        assert!("db,fork:80".parse::<ProxyTarget>().is_err());
+        assert!("db!!tcp:80".parse::<ProxyTarget>().is_err());
+        assert!("db:1:80".parse::<ProxyTarget>().is_err());
+        assert!("-db:80".parse::<ProxyTarget>().is_err());
+        assert!(ProxyTarget::new("db,reuseaddr", 80).is_err());
+    }
+}
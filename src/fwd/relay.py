@@ -0,0 +1,65 @@
+# Decodes datagrams framed by kubef (a two bytes length prefix) from every
+# tunnel connection and relays them to the target as UDP, framing the replies.
+# Without a target, the first frame of each connection names it as host:port
+import socket
+import struct
+import sys
+import threading
+
+
+def receive(stream, length):
+    data = stream.recv(length, socket.MSG_WAITALL) if length else b""
+    if len(data) < length:
+        raise EOFError
+    return data
+
+
+def frame(stream):
+    (length,) = struct.unpack("!H", receive(stream, 2))
+    return receive(stream, length)
+
+
+def reply(stream, datagrams):
+    while True:
+        try:
+            payload = datagrams.recv(65535)
+        except ConnectionRefusedError:
+            continue
+        except OSError:
+            return
+        stream.sendall(struct.pack("!H", len(payload)) + payload)
+
+
+def serve(stream, host, port):
+    try:
+        if host is None:
+            host, port = frame(stream).decode().rsplit(":", 1)
+            host = host.strip("[]")
+
+        family, kind, proto, _, address = socket.getaddrinfo(host, int(port), type=socket.SOCK_DGRAM)[0]
+        datagrams = socket.socket(family, kind, proto)
+        datagrams.connect(address)
+    except (EOFError, OSError, ValueError):
+        stream.close()
+        return
+
+    threading.Thread(target=reply, args=(stream, datagrams), daemon=True).start()
+
+    try:
+        while True:
+            datagrams.send(frame(stream))
+    except (EOFError, OSError):
+        pass
+    finally:
+        datagrams.close()
+        stream.close()
+
+
+def main(listen, host=None, port=None):
+    server = socket.create_server(("", int(listen)))
+    while True:
+        stream, _ = server.accept()
+        threading.Thread(target=serve, args=(stream, host, port), daemon=True).start()
+
+
+main(*sys.argv[1:])
@@ -1,7 +1,10 @@
 use anyhow::{Context, Ok, Result};
 
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use tokio::{net::TcpSocket, sync::RwLock};
+use tokio::{
+    net::{TcpSocket, UdpSocket},
+    sync::RwLock,
+};
 
 use ipnet::{IpAddrRange, IpNet};
 
@@ -43,31 +46,57 @@ impl SocketPool {
 
     pub async fn get_loopback(
         &self,
-        port: Option<u16>,
-    ) -> Result<(TcpSocket, Option<LoopbackToken>)> {
-        let (loopback, token) = match &self.pool {
-            Some(pool) => {
-                let mut lock = pool.write().await;
-                let loopback = lock
-                    .next()
-                    .context("No more loopback addresses available")?;
-
-                let token = LoopbackToken::new(loopback).await?;
-
-                (loopback, Some(token))
-            }
-            None => (Ipv4Addr::LOCALHOST.into(), None),
-        };
+        ports: impl IntoIterator<Item = Option<u16>>,
+    ) -> Result<(Vec<TcpSocket>, Option<LoopbackToken>)> {
+        let (loopback, token) = self.reserve().await?;
+
+        let sockets = ports
+            .into_iter()
+            .map(|port| {
+                let address = SocketAddr::from((loopback, port.unwrap_or(0)));
+                let socket = match loopback {
+                    IpAddr::V4(_) => TcpSocket::new_v4()?,
+                    IpAddr::V6(_) => TcpSocket::new_v6()?,
+                };
+
+                Self::bind(&socket, address)?;
+
+                Ok(socket)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok((sockets, token))
+    }
+
+    pub async fn get_loopback_udp(
+        &self,
+        ports: impl IntoIterator<Item = Option<u16>>,
+    ) -> Result<(Vec<UdpSocket>, Option<LoopbackToken>)> {
+        let (loopback, token) = self.reserve().await?;
+
+        let mut sockets = Vec::new();
+        for port in ports {
+            let address = SocketAddr::from((loopback, port.unwrap_or(0)));
 
-        let address = SocketAddr::from((loopback, port.unwrap_or(0)));
-        let socket = match loopback {
-            IpAddr::V4(_) => TcpSocket::new_v4()?,
-            IpAddr::V6(_) => TcpSocket::new_v6()?,
+            sockets.push(UdpSocket::bind(address).await?);
+        }
+
+        Ok((sockets, token))
+    }
+
+    async fn reserve(&self) -> Result<(IpAddr, Option<LoopbackToken>)> {
+        let Some(pool) = &self.pool else {
+            return Ok((Ipv4Addr::LOCALHOST.into(), None));
         };
 
-        Self::bind(&socket, address)?;
+        let mut lock = pool.write().await;
+        let loopback = lock
+            .next()
+            .context("No more loopback addresses available")?;
+
+        let token = LoopbackToken::new(loopback).await?;
 
-        Ok((socket, token))
+        Ok((loopback, Some(token)))
     }
 }
 
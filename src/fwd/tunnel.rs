@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::{
+    FutureExt,
+    future::{BoxFuture, Fuse},
+};
+use kube::api::Portforwarder;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+pub trait Duplex: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Duplex for T {}
+
+/// An opened port-forward stream along with the channel on which the kubelet
+/// reports failures, such as the container not listening on the port
+pub struct Tunnel {
+    pub forwarding: Portforwarder,
+    pub upstream: Box<dyn Duplex>,
+    pub closer: Fuse<BoxFuture<'static, Option<String>>>,
+    // Bytes sent by the pod while the tunnel was settling
+    pub buffer: Vec<u8>,
+}
+
+impl Tunnel {
+    pub fn new(mut forwarding: Portforwarder, pod_port: u16) -> Result<Self> {
+        let upstream = forwarding
+            .take_stream(pod_port)
+            .context("Failed to take stream")?;
+
+        let closer = forwarding
+            .take_error(pod_port)
+            .context("Failed to take error stream")?;
+
+        Ok(Self {
+            forwarding,
+            upstream: Box::new(upstream),
+            closer: closer.boxed().fuse(),
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Waits until the pod sends its first bytes or the grace period elapses
+    /// without the kubelet reporting an error, so failures can still be retried
+    pub async fn settle(&mut self, grace: Duration) -> Result<()> {
+        let deadline = tokio::time::sleep(grace);
+        tokio::pin!(deadline);
+
+        let mut closed = false;
+
+        let settled = loop {
+            tokio::select! {
+                biased;
+                Some(e) = &mut self.closer => break Err(anyhow::anyhow!(e)),
+                () = &mut deadline => break Ok(()),
+                read = self.upstream.read_buf(&mut self.buffer), if !closed => match read {
+                    // The error, if any, follows the end of the stream
+                    Ok(0) => closed = true,
+                    Ok(_) => break Ok(()),
+                    Err(e) => break Err(e.into()),
+                },
+            }
+        };
+
+        if settled.is_err() {
+            self.forwarding.abort();
+        }
+
+        settled
+    }
+}
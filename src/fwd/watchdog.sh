@@ -0,0 +1,30 @@
+# Runs the proxy until kubef stops renewing its heartbeat annotation, which
+# the downward API mirrors into /etc/kubef/annotations. Only changes of the
+# value matter, timed with the clock of the pod, so a skewed clock on the
+# machine running kubef cannot expire it early
+expire_after=$1
+shift
+
+"$@" &
+proxy=$!
+
+trap 'kill "$proxy"; exit 0' TERM INT
+
+last=
+renewed=$(date +%s)
+
+while kill -0 "$proxy" 2>/dev/null; do
+    sleep 10
+
+    heartbeat=$(sed -n 's/^kubef\.io\/heartbeat="\(.*\)"$/\1/p' /etc/kubef/annotations 2>/dev/null)
+    if [ "$heartbeat" != "$last" ]; then
+        last=$heartbeat
+        renewed=$(date +%s)
+    elif [ $(($(date +%s) - renewed)) -gt "$expire_after" ]; then
+        echo "No heartbeat from kubef for over ${expire_after}s, exiting"
+        kill "$proxy"
+        exit 0
+    fi
+done
+
+wait "$proxy"
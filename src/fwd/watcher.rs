@@ -1,92 +1,228 @@
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Debug,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
     sync::{
-        Arc,
-        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
-use futures::StreamExt;
-use k8s_openapi::api::{
-    apps::v1::Deployment,
-    core::v1::{Pod, Service},
+use futures::{StreamExt, future};
+use k8s_openapi::{
+    NamespaceResourceScope,
+    api::{
+        apps::v1::{DaemonSet, Deployment, ReplicaSet, StatefulSet},
+        batch::v1::Job,
+        core::v1::{Node, Pod, Service, ServicePort},
+        discovery::v1::EndpointSlice,
+    },
+    apimachinery::pkg::apis::meta::v1::LabelSelector,
 };
 use kube::{
-    Api, Client,
-    api::PartialObjectMeta,
+    Api, Client, ResourceExt,
+    api::{DynamicObject, PartialObjectMeta},
     client::scope::Namespace,
-    core::Selector,
+    core::{Expression, GroupVersionKind, Selector, TypeMeta},
+    discovery::{self, Scope},
     runtime::{
-        WatchStreamExt, predicates,
-        reflector::{self, ReflectHandle, Store},
+        WatchStreamExt,
+        reflector::{self, ObjectRef, Store},
         watcher::{self},
     },
 };
-use tokio::task::JoinHandle;
-use tracing::debug;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{sync::Notify, task::JoinHandle};
+use tracing::{debug, info, warn};
+
+use crate::{
+    cnf::{
+        BackendRef,
+        schema::{
+            CustomMatch, Discovery, LabelExpression, LabelMatch, LabelOperator, Preference,
+            Resource, ResourceSelector, SelectorPolicy, StatefulSetMatch,
+        },
+    },
+    fwd::connections::Connections,
+};
+
+const STATEFULSET_POD_NAME: &str = "statefulset.kubernetes.io/pod-name";
+const SERVICE_NAME: &str = "kubernetes.io/service-name";
+const TOPOLOGY_ZONE: &str = "topology.kubernetes.io/zone";
+
+pub struct Selection {
+    pub selector: Selector,
+    pub fields: Option<String>,
+    pub pattern: Option<String>,
+    pub endpoints: Option<String>,
+    pub ports: Vec<ServicePort>,
+}
+
+impl From<Selector> for Selection {
+    fn from(selector: Selector) -> Self {
+        Self {
+            selector,
+            fields: None,
+            pattern: None,
+            endpoints: None,
+            ports: Vec::new(),
+        }
+    }
+}
 
-use crate::cnf::schema::{Resource, ResourceSelector, SelectorPolicy};
+#[derive(Clone, Default)]
+pub struct Options {
+    pub policy: SelectorPolicy,
+    pub include_not_ready: bool,
+    pub prefer: Preference,
+}
 
-type Object = PartialObjectMeta<Pod>;
+impl From<&Resource> for Options {
+    fn from(resource: &Resource) -> Self {
+        Self {
+            policy: resource.policy.unwrap_or_default(),
+            include_not_ready: resource.include_not_ready.unwrap_or_default(),
+            prefer: resource.prefer.clone().unwrap_or_default(),
+        }
+    }
+}
 
 pub struct Watcher {
-    store: Store<Object>,
-    subscriber: ReflectHandle<Object>,
+    source: Source,
+    notify: Arc<Notify>,
     counter: AtomicUsize,
-    policy: SelectorPolicy,
+    pinned: Mutex<Option<(String, String)>>,
+    penalties: Mutex<HashMap<String, Instant>>,
+    unhealthy: Mutex<HashSet<String>>,
+    connected: Arc<AtomicBool>,
+    connections: Connections,
+    options: Options,
+    pattern: Option<String>,
+    nodes: Option<(Store<PartialObjectMeta<Node>>, JoinHandle<()>)>,
     handle: JoinHandle<()>,
 }
 
+enum Source {
+    Pods(Store<Pod>),
+    Slices(Store<EndpointSlice>),
+}
+
+#[derive(Clone)]
+pub enum Backend {
+    Pod(Arc<Pod>),
+    Endpoint(Arc<EndpointSlice>, usize),
+}
+
 impl Watcher {
-    pub async fn new(api: Api<Pod>, selector: &Selector, policy: SelectorPolicy) -> Result<Self> {
-        let (store, writer) = reflector::store_shared(256);
-
-        let config = watcher::Config::default().labels_from(selector);
-        let subscriber = writer.subscribe().context("Failed to create subscriber")?;
-
-        let handle = tokio::spawn(
-            watcher::metadata_watcher(api, config)
-                .reflect(writer)
-                .default_backoff()
-                .applied_objects()
-                .predicate_filter(predicates::labels)
-                .for_each(|_| async {}),
-        );
+    pub async fn new(
+        client: Client,
+        namespace: &str,
+        selection: &Selection,
+        options: Options,
+    ) -> Result<Self> {
+        let connected = Arc::new(AtomicBool::new(true));
+
+        // Pods do not carry their zone, it has to be read from the node they run on
+        let nodes = if options.prefer.zone.is_some() && selection.endpoints.is_none() {
+            match nodes(client.clone()).await {
+                Ok(nodes) => Some(nodes),
+                Err(e) => {
+                    warn!(
+                        "Cannot watch nodes, the zone preference is ignored: {:#}",
+                        e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let notify = Arc::new(Notify::new());
 
-        tokio::time::timeout(Duration::from_secs(10), store.wait_until_ready())
-            .await
-            .context("Timeout waiting for pods")?
-            .context("Failed to wait for pods")?;
+        let (source, handle) = if let Some(service) = &selection.endpoints {
+            let api = Api::<EndpointSlice>::namespaced(client, namespace);
+            let config = watcher::Config::default().labels(&format!("{SERVICE_NAME}={service}"));
+
+            let (store, handle) = reflect(api, config, connected.clone(), notify.clone()).await?;
+
+            (Source::Slices(store), handle)
+        } else {
+            let api = Api::<Pod>::namespaced(client, namespace);
+            let mut config = watcher::Config::default().labels_from(&selection.selector);
+            if let Some(fields) = &selection.fields {
+                config = config.fields(fields);
+            }
+
+            let (store, handle) = reflect(api, config, connected.clone(), notify.clone()).await?;
+
+            (Source::Pods(store), handle)
+        };
 
         Ok(Self {
-            store,
-            subscriber,
+            source,
+            notify,
             counter: AtomicUsize::new(0),
-            policy,
+            pinned: Mutex::new(None),
+            penalties: Mutex::new(HashMap::new()),
+            unhealthy: Mutex::new(HashSet::new()),
+            connected,
+            connections: Connections::default(),
+            options,
+            pattern: selection.pattern.clone(),
+            nodes,
             handle,
         })
     }
 
     pub fn is_empty(&self) -> bool {
-        self.store.is_empty()
+        self.available().is_empty()
     }
 
-    pub fn get(&self) -> Option<Arc<Object>> {
-        if self.store.is_empty() {
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub fn get(&self, client: SocketAddr) -> Option<Backend> {
+        let mut state = self.available();
+        if state.is_empty() {
             return None;
         }
 
-        let state = self.store.state();
-        let counter = match self.policy {
-            SelectorPolicy::Sticky => self.counter.load(Ordering::Relaxed),
-            SelectorPolicy::RoundRobin => self.counter.fetch_add(1, Ordering::Relaxed),
-        };
+        self.skip_penalized(&mut state);
+        self.prefer_topology(&mut state);
 
-        let index = if state.len().is_power_of_two() {
-            counter & (state.len() - 1)
-        } else {
-            counter % state.len()
+        let index = match self.options.policy {
+            SelectorPolicy::Sticky => self.pin(&state),
+            SelectorPolicy::RoundRobin => {
+                wrap(self.counter.fetch_add(1, Ordering::Relaxed), state.len())
+            }
+            SelectorPolicy::LeastConnections => {
+                // Rotate the starting point so that ties are broken round robin
+                let offset = self.counter.fetch_add(1, Ordering::Relaxed);
+
+                (0..state.len())
+                    .map(|i| wrap(offset.wrapping_add(i), state.len()))
+                    .min_by_key(|&i| self.connections.count(state[i].name()))
+                    .unwrap_or_default()
+            }
+            SelectorPolicy::IpHash => {
+                // Rendezvous hashing only remaps the clients of pods that come or go
+                (0..state.len())
+                    .max_by_key(|&i| {
+                        let mut hasher = DefaultHasher::new();
+
+                        client.ip().hash(&mut hasher);
+                        state[i].name().hash(&mut hasher);
+
+                        hasher.finish()
+                    })
+                    .unwrap_or_default()
+            }
+            SelectorPolicy::Random => rand::random_range(0..state.len()),
         };
 
         debug!("Selecting pod {} of {}", index, state.len());
@@ -94,46 +230,619 @@ impl Watcher {
         state.get(index).cloned()
     }
 
-    pub async fn next(&mut self) -> Result<Arc<Object>> {
-        self.subscriber.next().await.context("Cannot get next pod")
+    pub fn set_unhealthy(&self, unhealthy: HashSet<String>) -> HashSet<String> {
+        let mut current = self
+            .unhealthy
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        std::mem::replace(&mut current, unhealthy)
+    }
+
+    pub fn penalize(&self, backend: &Backend, duration: Duration) {
+        let mut penalties = self
+            .penalties
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        penalties.insert(backend.uid().to_owned(), Instant::now() + duration);
+    }
+
+    fn skip_penalized(&self, state: &mut Vec<Backend>) {
+        let mut penalties = self
+            .penalties
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+
+        penalties.retain(|_, until| *until > now);
+
+        // Penalized pods are still better than no pods at all
+        if state
+            .iter()
+            .any(|backend| !penalties.contains_key(backend.uid()))
+        {
+            state.retain(|backend| !penalties.contains_key(backend.uid()));
+        }
+    }
+
+    fn prefer_topology(&self, state: &mut Vec<Backend>) {
+        let Preference { zone, node } = &self.options.prefer;
+
+        // Prefer the same node first, then the same zone, then anything
+        if node.is_some()
+            && state
+                .iter()
+                .any(|backend| backend.node() == node.as_deref())
+        {
+            state.retain(|backend| backend.node() == node.as_deref());
+        } else if zone.is_some()
+            && state
+                .iter()
+                .any(|backend| self.zone(backend).as_deref() == zone.as_deref())
+        {
+            state.retain(|backend| self.zone(backend).as_deref() == zone.as_deref());
+        }
+    }
+
+    fn zone(&self, backend: &Backend) -> Option<String> {
+        match backend {
+            Backend::Pod(_) => {
+                let (nodes, _) = self.nodes.as_ref()?;
+                let node = nodes.get(&ObjectRef::new(backend.node()?))?;
+
+                node.labels().get(TOPOLOGY_ZONE).cloned()
+            }
+            Backend::Endpoint(slice, index) => slice.endpoints[*index].zone.clone(),
+        }
+    }
+
+    fn pin(&self, state: &[Backend]) -> usize {
+        let mut pinned = self.pinned.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(index) = pinned
+            .as_ref()
+            .and_then(|(uid, _)| state.iter().position(|backend| backend.uid() == uid))
+        {
+            return index;
+        }
+
+        // Re-pin to the first pod by name so that the choice is deterministic
+        let index = (0..state.len())
+            .min_by_key(|&i| state[i].name())
+            .unwrap_or_default();
+
+        let backend = &state[index];
+        let previous = pinned.replace((backend.uid().to_owned(), backend.name().to_owned()));
+
+        if let Some((_, name)) = previous {
+            info!("Re-pinning from pod {} to {}", name, backend.name());
+        } else {
+            info!("Pinning to pod {}", backend.name());
+        }
+
+        index
+    }
+
+    pub fn connections(&self) -> &Connections {
+        &self.connections
+    }
+
+    pub async fn next(&self) -> Result<()> {
+        self.notify.notified().await;
+
+        Ok(())
+    }
+
+    fn available(&self) -> Vec<Backend> {
+        let mut state = self.backends();
+        let unhealthy = self
+            .unhealthy
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        state.retain(|backend| !unhealthy.contains(backend.uid()));
+        state
+    }
+
+    pub fn backends(&self) -> Vec<Backend> {
+        let include_not_ready = self.options.include_not_ready;
+
+        let mut state: Vec<_> = match &self.source {
+            Source::Pods(store) => store
+                .state()
+                .into_iter()
+                .map(Backend::Pod)
+                .filter(|backend| backend.is_eligible(include_not_ready))
+                .collect(),
+            Source::Slices(store) => {
+                let mut seen = HashSet::new();
+
+                // Dual-stack services list the same pod in one slice per address family
+                store
+                    .state()
+                    .into_iter()
+                    .flat_map(|slice| {
+                        (0..slice.endpoints.len()).map(move |i| Backend::Endpoint(slice.clone(), i))
+                    })
+                    .filter(|backend| backend.is_eligible(include_not_ready))
+                    .filter(|backend| seen.insert(backend.name().to_owned()))
+                    .collect()
+            }
+        };
+
+        if let Some(pattern) = &self.pattern {
+            state.retain(|backend| glob(pattern, backend.name()));
+        }
+
+        state
     }
 }
 
 impl Drop for Watcher {
     fn drop(&mut self) {
         self.handle.abort();
+
+        if let Some((_, handle)) = &self.nodes {
+            handle.abort();
+        }
+    }
+}
+
+impl Backend {
+    pub fn name(&self) -> &str {
+        match self {
+            Backend::Pod(pod) => pod.metadata.name.as_deref().unwrap_or_default(),
+            Backend::Endpoint(slice, index) => slice.endpoints[*index]
+                .target_ref
+                .as_ref()
+                .and_then(|target| target.name.as_deref())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn uid(&self) -> &str {
+        let uid = match self {
+            Backend::Pod(pod) => pod.metadata.uid.as_deref(),
+            Backend::Endpoint(slice, index) => slice.endpoints[*index]
+                .target_ref
+                .as_ref()
+                .and_then(|target| target.uid.as_deref()),
+        };
+
+        uid.unwrap_or_else(|| self.name())
+    }
+
+    pub fn node(&self) -> Option<&str> {
+        match self {
+            Backend::Pod(pod) => pod.spec.as_ref()?.node_name.as_deref(),
+            Backend::Endpoint(slice, index) => slice.endpoints[*index].node_name.as_deref(),
+        }
+    }
+
+    pub fn ip(&self) -> Option<&str> {
+        match self {
+            Backend::Pod(pod) => pod.status.as_ref()?.pod_ip.as_deref(),
+            Backend::Endpoint(slice, index) => slice.endpoints[*index]
+                .addresses
+                .first()
+                .map(String::as_str),
+        }
+    }
+
+    fn is_eligible(&self, include_not_ready: bool) -> bool {
+        match self {
+            Backend::Pod(pod) => is_eligible(pod, include_not_ready),
+            Backend::Endpoint(slice, index) => {
+                let endpoint = &slice.endpoints[*index];
+                let is_pod = endpoint
+                    .target_ref
+                    .as_ref()
+                    .is_some_and(|target| target.kind.as_deref() == Some("Pod"));
+
+                let conditions = endpoint.conditions.clone().unwrap_or_default();
+                let terminating = conditions.terminating.unwrap_or(false);
+
+                // A missing ready condition has to be interpreted as ready
+                let ready = conditions.ready.unwrap_or(true);
+
+                is_pod && !self.name().is_empty() && !terminating && (ready || include_not_ready)
+            }
+        }
+    }
+}
+
+async fn reflect<K>(
+    api: Api<K>,
+    config: watcher::Config,
+    connected: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+) -> Result<(Store<K>, JoinHandle<()>)>
+where
+    K: kube::Resource + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+    K::DynamicType: Default + Eq + Hash + Clone + Send + Sync,
+{
+    let (store, writer) = reflector::store();
+
+    // The task wakes up waiters itself instead of feeding a subscriber, which
+    // would stall the reflector once nobody reads from it
+    let handle = tokio::spawn(
+        watcher::watcher(api, config)
+            .reflect(writer)
+            .default_backoff()
+            .for_each(move |event| {
+                connected.store(event.is_ok(), Ordering::Relaxed);
+                notify.notify_one();
+
+                future::ready(())
+            }),
+    );
+
+    tokio::time::timeout(Duration::from_secs(10), store.wait_until_ready())
+        .await
+        .context("Timeout waiting for pods")?
+        .context("Failed to wait for pods")?;
+
+    Ok((store, handle))
+}
+
+async fn nodes(client: Client) -> Result<(Store<PartialObjectMeta<Node>>, JoinHandle<()>)> {
+    let api = Api::<Node>::all(client);
+    let (store, writer) = reflector::store();
+
+    let handle = tokio::spawn(
+        watcher::metadata_watcher(api, watcher::Config::default())
+            .default_backoff()
+            .reflect(writer)
+            .applied_objects()
+            .for_each(|_| async {}),
+    );
+
+    let ready = tokio::time::timeout(Duration::from_secs(10), store.wait_until_ready()).await;
+    if let Err(e) = ready
+        .context("Timeout waiting for nodes")
+        .and_then(|ready| ready.context("Failed to wait for nodes"))
+    {
+        handle.abort();
+
+        return Err(e);
     }
+
+    Ok((store, handle))
 }
 
-pub async fn select(client: &Client, resource: &Resource) -> Result<Selector> {
-    match &resource.selector {
-        ResourceSelector::Label(labels) => Ok(Selector::from_iter(labels.clone())),
+pub async fn select(client: &Client, backend: &BackendRef<'_>) -> Result<Selection> {
+    let namespace = backend.namespace;
+
+    if matches!(backend.discovery, Discovery::EndpointSlices)
+        && !matches!(backend.selector, ResourceSelector::Service(_))
+    {
+        anyhow::bail!("EndpointSlice discovery requires a service selector");
+    }
+
+    match backend.selector {
+        ResourceSelector::Label(labels) => {
+            let selector = labels
+                .iter()
+                .map(expression)
+                .collect::<Result<Selector>>()?;
+
+            Ok(selector.into())
+        }
         ResourceSelector::Deployment(name) => {
-            let deployment = client
-                .get::<Deployment>(name, &Namespace::from(resource.namespace.clone()))
-                .await?;
+            let selector = workload::<Deployment>(client, name, namespace, |deployment| {
+                deployment.spec.map(|spec| spec.selector)
+            });
+
+            Ok(selector.await?.into())
+        }
+        ResourceSelector::StatefulSet(target) => {
+            let (name, ordinal) = match target {
+                StatefulSetMatch::Name(name) => (name, None),
+                StatefulSetMatch::Ordinal { name, ordinal } => (name, Some(ordinal)),
+            };
+
+            let mut selector = workload::<StatefulSet>(client, name, namespace, |statefulset| {
+                statefulset.spec.map(|spec| spec.selector)
+            })
+            .await?;
+
+            if let Some(ordinal) = ordinal {
+                selector.extend([Expression::Equal(
+                    STATEFULSET_POD_NAME.to_string(),
+                    format!("{name}-{ordinal}"),
+                )]);
+            }
 
-            let selector = deployment
-                .spec
-                .context("Deployment has no spec")?
-                .selector
-                .try_into()?;
+            Ok(selector.into())
+        }
+        ResourceSelector::DaemonSet(name) => {
+            let selector = workload::<DaemonSet>(client, name, namespace, |daemonset| {
+                daemonset.spec.map(|spec| spec.selector)
+            });
+
+            Ok(selector.await?.into())
+        }
+        ResourceSelector::ReplicaSet(name) => {
+            let selector = workload::<ReplicaSet>(client, name, namespace, |replicaset| {
+                replicaset.spec.map(|spec| spec.selector)
+            });
 
-            Ok(selector)
+            Ok(selector.await?.into())
+        }
+        ResourceSelector::Job(name) => {
+            let selector = workload::<Job>(client, name, namespace, |job| {
+                job.spec.and_then(|spec| spec.selector)
+            });
+
+            Ok(selector.await?.into())
         }
         ResourceSelector::Service(name) => {
             let service = client
-                .get::<Service>(name, &Namespace::from(resource.namespace.clone()))
+                .get::<Service>(name, &Namespace::from(namespace.to_string()))
                 .await?;
 
-            let selector = service
-                .spec
-                .context("Service has no spec")?
-                .selector
-                .context("Service has no selector")?;
+            let spec = service.spec.context("Service has no spec")?;
+
+            // EndpointSlices can be managed without a selector on the Service
+            let (selector, endpoints) = match backend.discovery {
+                Discovery::Pods => (spec.selector.context("Service has no selector")?, None),
+                Discovery::EndpointSlices => (BTreeMap::new(), Some(name.clone())),
+            };
 
             // TODO: it's a hack, kube-rs does something horrible behind the scenes
-            Ok(Selector::from_iter(selector))
+            Ok(Selection {
+                endpoints,
+                ports: spec.ports.unwrap_or_default(),
+                ..Selector::from_iter(selector).into()
+            })
         }
+        ResourceSelector::Pod(name) if name.contains(['*', '?']) => Ok(Selection {
+            pattern: Some(name.clone()),
+            ..Selector::default().into()
+        }),
+        ResourceSelector::Pod(name) => Ok(Selection {
+            fields: Some(format!("metadata.name={name}")),
+            ..Selector::default().into()
+        }),
+        ResourceSelector::Custom(target) => Ok(custom(client, namespace, target).await?.into()),
+    }
+}
+
+fn expression(label: &LabelMatch) -> Result<Expression> {
+    let LabelExpression {
+        key,
+        operator,
+        values,
+    } = match label {
+        LabelMatch::Equal((key, value)) => {
+            return Ok(Expression::Equal(key.clone(), value.clone()));
+        }
+        LabelMatch::Expression(expression) => expression,
+    };
+
+    match operator {
+        LabelOperator::In | LabelOperator::NotIn if values.is_empty() => {
+            anyhow::bail!("Label expression {operator:?} on {key} requires values")
+        }
+        LabelOperator::Exists | LabelOperator::DoesNotExist if !values.is_empty() => {
+            anyhow::bail!("Label expression {operator:?} on {key} does not accept values")
+        }
+        LabelOperator::In => Ok(Expression::In(
+            key.clone(),
+            values.iter().cloned().collect(),
+        )),
+        LabelOperator::NotIn => Ok(Expression::NotIn(
+            key.clone(),
+            values.iter().cloned().collect(),
+        )),
+        LabelOperator::Exists => Ok(Expression::Exists(key.clone())),
+        LabelOperator::DoesNotExist => Ok(Expression::DoesNotExist(key.clone())),
+    }
+}
+
+async fn custom(client: &Client, namespace: &str, target: &CustomMatch) -> Result<Selector> {
+    let CustomMatch {
+        api_version,
+        kind,
+        name,
+    } = target;
+
+    let gvk = GroupVersionKind::try_from(TypeMeta {
+        api_version: api_version.clone(),
+        kind: kind.clone(),
+    })?;
+
+    let (resource, capabilities) = discovery::pinned_kind(client, &gvk).await?;
+    let api = match capabilities.scope {
+        Scope::Namespaced => {
+            Api::<DynamicObject>::namespaced_with(client.clone(), namespace, &resource)
+        }
+        Scope::Cluster => Api::<DynamicObject>::all_with(client.clone(), &resource),
+    };
+
+    let object = api.get(name).await?;
+    let selector = object
+        .data
+        .pointer("/spec/selector")
+        .cloned()
+        .with_context(|| format!("{kind} {name} has no selector"))?;
+
+    let selector: LabelSelector = serde_json::from_value(selector)
+        .with_context(|| format!("{kind} {name} has an invalid selector"))?;
+
+    Ok(selector.try_into()?)
+}
+
+async fn workload<K>(
+    client: &Client,
+    name: &str,
+    namespace: &str,
+    selector: impl FnOnce(K) -> Option<LabelSelector>,
+) -> Result<Selector>
+where
+    K: kube::Resource<Scope = NamespaceResourceScope>
+        + Serialize
+        + DeserializeOwned
+        + Clone
+        + Debug,
+    K::DynamicType: Default,
+{
+    let kind = K::kind(&K::DynamicType::default()).into_owned();
+    let object = client
+        .get::<K>(name, &Namespace::from(namespace.to_string()))
+        .await?;
+
+    let selector = selector(object)
+        .with_context(|| format!("{kind} {name} has no selector"))?
+        .try_into()?;
+
+    Ok(selector)
+}
+
+fn wrap(counter: usize, len: usize) -> usize {
+    if len.is_power_of_two() {
+        counter & (len - 1)
+    } else {
+        counter % len
+    }
+}
+
+fn is_eligible(pod: &Pod, include_not_ready: bool) -> bool {
+    if pod.metadata.deletion_timestamp.is_some() {
+        return false;
+    }
+
+    let Some(status) = &pod.status else {
+        return false;
+    };
+
+    if status.phase.as_deref() != Some("Running") {
+        return false;
+    }
+
+    include_not_ready
+        || status
+            .conditions
+            .iter()
+            .flatten()
+            .any(|condition| condition.type_ == "Ready" && condition.status == "True")
+}
+
+fn glob(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let name = name.as_bytes();
+
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_literals() {
+        assert!(glob("api-0", "api-0"));
+        assert!(!glob("api-0", "api-1"));
+        assert!(!glob("api", "api-0"));
+        assert!(!glob("api-0", "api"));
+    }
+
+    #[test]
+    fn glob_matches_trailing_star() {
+        assert!(glob("api-*", "api-7d9f-x2"));
+        assert!(glob("api-*", "api-"));
+        assert!(glob("*", "anything"));
+        assert!(!glob("api-*", "web-7d9f-x2"));
+    }
+
+    #[test]
+    fn glob_matches_question_mark() {
+        assert!(glob("api-?", "api-0"));
+        assert!(!glob("api-?", "api-"));
+        assert!(!glob("api-?", "api-10"));
+    }
+
+    #[test]
+    fn glob_backtracks() {
+        assert!(glob("*-worker-*", "api-worker-worker-0"));
+        assert!(glob("a*b*c", "aXbYbZc"));
+        assert!(glob("*-?", "api-web-0"));
+        assert!(!glob("a*b*c", "aXbYbZ"));
+        assert!(!glob("*-worker", "api-worker-0"));
+    }
+
+    fn label(yaml: &str) -> Result<Vec<Expression>> {
+        let ResourceSelector::Label(labels) = serde_yaml_ng::from_str(yaml)? else {
+            anyhow::bail!("Not a label selector");
+        };
+
+        labels.iter().map(expression).collect()
+    }
+
+    #[test]
+    fn parses_mixed_label_matches() {
+        let expressions = label(
+            r#"
+            type: label
+            match:
+              - ["app", "api"]
+              - key: track
+                operator: NotIn
+                values: ["canary"]
+              - key: tier
+                operator: Exists
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            expressions,
+            [
+                Expression::Equal("app".to_string(), "api".to_string()),
+                Expression::NotIn("track".to_string(), ["canary".to_string()].into()),
+                Expression::Exists("tier".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_label_expressions() {
+        let without_values = "type: label\nmatch:\n  - key: track\n    operator: In\n";
+        assert!(label(without_values).is_err());
+
+        let with_values =
+            "type: label\nmatch:\n  - key: track\n    operator: Exists\n    values: [a]\n";
+        assert!(label(with_values).is_err());
+
+        let unknown_operator = "type: label\nmatch:\n  - key: track\n    operator: Equals\n";
+        assert!(label(unknown_operator).is_err());
+
+        let unknown_field = "type: label\nmatch:\n  - key: track\n    operator: In\n    value: a\n";
+        assert!(label(unknown_field).is_err());
     }
 }